@@ -0,0 +1,189 @@
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    dos,
+    engine::{EngineData, ExitStatus},
+    keyboard,
+    memory::MEMORY_TOP,
+    timer, video,
+};
+
+/// Segment of the BIOS ROM where the interrupt stubs are
+pub const ROM_SEGMENT: u16 = 0xF000;
+/// Every interrupt gets 8 bytes of ROM for its stub
+const STUB_SIZE: u16 = 8;
+
+/// Variables in the BIOS data area at 0040:0000
+const BDA_COM_PORTS: u64 = 0x400;
+const BDA_LPT_PORTS: u64 = 0x408;
+const BDA_EQUIPMENT: u64 = 0x410;
+const BDA_MEMORY_SIZE: u64 = 0x413;
+const BDA_HARD_DISKS: u64 = 0x475;
+const BDA_CHAR_HEIGHT: u64 = 0x485;
+const BDA_KEYBOARD_STATUS: u64 = 0x496;
+
+/// Two serial ports, one printer port and 80x25 color video
+const EQUIPMENT: u16 = (1 << 14) | (2 << 9) | (0b10 << 4);
+const COM_PORTS: [u16; 4] = [0x3F8, 0x2F8, 0, 0];
+const LPT_PORTS: [u16; 3] = [0x378, 0, 0];
+
+/// Date and model byte of an AT compatible BIOS at the end of the ROM
+const BIOS_DATE: (u64, &[u8]) = (0xFFFF5, b"01/01/92");
+const MODEL: (u64, u8) = (0xFFFFE, 0xFC);
+
+const TRAP_FLAG: u64 = 1 << 8;
+pub const INTERRUPT_FLAG: u64 = 1 << 9;
+
+/// Hardware interrupts of the two PICs, their stubs end with IRET to restore the flags
+fn is_irq(num: u8) -> bool {
+    matches!(num, 0x08..=0x0F | 0x70..=0x77)
+}
+
+/// Interrupts that only return, the user timer tick and Ctrl-Break
+fn is_empty(num: u8) -> bool {
+    matches!(num, 0x1B | 0x1C)
+}
+
+/// Segment and offset of the ROM stub of the interrupt
+pub fn stub_address(num: u8) -> (u16, u16) {
+    (ROM_SEGMENT, num as u16 * STUB_SIZE)
+}
+
+/// The interrupt was called from a ROM stub, e.g. when a program chains to the old handler
+fn in_rom(cs: u64) -> bool {
+    cs == ROM_SEGMENT as u64
+}
+
+/// Segment and offset of the handler in the interrupt vector table
+pub fn vector(emu: &Unicorn<EngineData>, num: u8) -> (u16, u16) {
+    let offset = read_word(emu, num as u64 * 4);
+    let segment = read_word(emu, num as u64 * 4 + 2);
+    (segment, offset)
+}
+
+/// Code of the stub, `int n` lets the interrupt hook run the Rust handler.
+///
+/// The software interrupts return with `retf 2` so the flags the handler set are kept.
+fn stub(num: u8) -> [u8; 5] {
+    if is_irq(num) {
+        [0xCD, num, 0xCF, 0x90, 0x90]
+    } else {
+        [0xCD, num, 0xCA, 0x02, 0x00]
+    }
+}
+
+fn write_word(emu: &mut Unicorn<EngineData>, addr: u64, value: u16) {
+    emu.mem_write(addr, &value.to_le_bytes()).unwrap();
+}
+
+/// Write the ROM stubs, point the interrupt vector table at them and fill the BIOS data area
+pub fn init(emu: &mut Unicorn<EngineData>) {
+    for num in 0..=255u8 {
+        let (segment, offset) = stub_address(num);
+        emu.mem_write(segment as u64 * 16 + offset as u64, &stub(num))
+            .unwrap();
+        write_word(emu, num as u64 * 4, offset);
+        write_word(emu, num as u64 * 4 + 2, segment);
+    }
+    emu.mem_write(BIOS_DATE.0, BIOS_DATE.1).unwrap();
+    emu.mem_write(MODEL.0, &[MODEL.1]).unwrap();
+
+    for (idx, port) in COM_PORTS.iter().enumerate() {
+        write_word(emu, BDA_COM_PORTS + idx as u64 * 2, *port);
+    }
+    for (idx, port) in LPT_PORTS.iter().enumerate() {
+        write_word(emu, BDA_LPT_PORTS + idx as u64 * 2, *port);
+    }
+    write_word(emu, BDA_EQUIPMENT, EQUIPMENT);
+    write_word(emu, BDA_MEMORY_SIZE, MEMORY_TOP / 64);
+    // Drive C: is the only hard disk
+    emu.mem_write(BDA_HARD_DISKS, &[1]).unwrap();
+    write_word(emu, BDA_CHAR_HEIGHT, 16);
+    // Enhanced 101 key keyboard
+    emu.mem_write(BDA_KEYBOARD_STATUS, &[0x10]).unwrap();
+
+    video::init(emu);
+    keyboard::init(emu);
+    timer::init(emu);
+}
+
+fn read_word(emu: &Unicorn<EngineData>, addr: u64) -> u16 {
+    let mut buf = [0u8; 2];
+    emu.mem_read(addr, &mut buf).unwrap();
+    u16::from_le_bytes(buf)
+}
+
+/// Equipment list (INT 11h)
+fn int11(emu: &mut Unicorn<EngineData>) {
+    let equipment = read_word(emu, BDA_EQUIPMENT);
+    emu.reg_write(RegisterX86::AX, equipment as u64).unwrap();
+}
+
+/// Conventional memory size in KB (INT 12h)
+fn int12(emu: &mut Unicorn<EngineData>) {
+    let size = read_word(emu, BDA_MEMORY_SIZE);
+    emu.reg_write(RegisterX86::AX, size as u64).unwrap();
+}
+
+fn push(emu: &mut Unicorn<EngineData>, value: u64) {
+    let ss = emu.reg_read(RegisterX86::SS).unwrap();
+    let sp = emu.reg_read(RegisterX86::SP).unwrap().wrapping_sub(2) & 0xffff;
+    emu.reg_write(RegisterX86::SP, sp).unwrap();
+    write_word(emu, ss * 16 + sp, value as u16);
+}
+
+/// Deliver the interrupt to a handler the program installed like the cpu does, push
+/// FLAGS, CS and IP, clear IF and TF and jump to the vector
+pub fn deliver(emu: &mut Unicorn<EngineData>, segment: u16, offset: u16) {
+    let flags = emu.reg_read(RegisterX86::EFLAGS).unwrap();
+    let cs = emu.reg_read(RegisterX86::CS).unwrap();
+    // IP already points after the INT instruction
+    let ip = emu.reg_read(RegisterX86::IP).unwrap();
+    push(emu, flags);
+    push(emu, cs);
+    push(emu, ip);
+
+    let flags = flags & !(TRAP_FLAG | INTERRUPT_FLAG);
+    emu.reg_write(RegisterX86::EFLAGS, flags).unwrap();
+    emu.reg_write(RegisterX86::CS, segment as u64).unwrap();
+    emu.reg_write(RegisterX86::IP, offset as u64).unwrap();
+}
+
+/// Handle an INT instruction, the emulator's handler runs when the vector still points
+/// at the ROM stub or when the stub itself was called. Other vectors go to the program's handler.
+pub fn interrupt(emu: &mut Unicorn<EngineData>, num: u32) {
+    let num = num as u8;
+    let cs = emu.reg_read(RegisterX86::CS).unwrap();
+    let (segment, offset) = vector(emu, num);
+    if !in_rom(cs) && (segment, offset) != stub_address(num) {
+        if emu.get_data().verbose {
+            println!("Interrupt 0x{num:x} to the program's handler at {segment:04x}:{offset:04x}");
+        }
+        deliver(emu, segment, offset);
+        return;
+    }
+
+    if num == 0x08 {
+        timer::int08(emu);
+    } else if num == 0x10 {
+        video::int10(emu);
+    } else if num == 0x11 {
+        int11(emu);
+    } else if num == 0x12 {
+        int12(emu);
+    } else if num == 0x16 {
+        keyboard::int16(emu);
+    } else if num == 0x1A {
+        timer::int1a(emu);
+    } else if num == 0x20 {
+        dos::int20(emu);
+    } else if num == 0x21 {
+        dos::int21(emu);
+    } else if is_empty(num) {
+        // Nothing to do, the stub returns right away
+    } else {
+        eprintln!("Unimplemented interrupt 0x{num:x}, exiting...");
+        emu.get_data_mut().exit = Some(ExitStatus::Unimplemented);
+        emu.emu_stop().unwrap();
+    }
+}
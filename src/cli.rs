@@ -1,5 +1,16 @@
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
 use clap::Parser;
 
+use crate::{
+    clock::parse_time,
+    drive::drive_number,
+    engine::{DEFAULT_FAILURE_CODE, Failure},
+    memory::{FIRST_MCB, MEMORY_TOP},
+    timer::DEFAULT_IPS,
+};
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CliArgs {
@@ -15,12 +26,146 @@ pub struct CliArgs {
     #[arg(short, long)]
     pub verbose: bool,
 
+    /// Host directory mounted as drive C:, defaults to the directory of the program
+    #[arg(long)]
+    pub host_dir: Option<PathBuf>,
+
+    /// Mount a host directory as an additional drive, e.g. `--drive D=/path/to/dir`
+    #[arg(long = "drive", value_name = "LETTER=DIR", value_parser = parse_drive)]
+    pub drives: Vec<(u8, PathBuf)>,
+
+    /// Mount a host directory as a drive the program can't change, e.g. `--ro-drive D=/cdrom`
+    #[arg(long = "ro-drive", value_name = "LETTER=DIR", value_parser = parse_drive)]
+    pub ro_drives: Vec<(u8, PathBuf)>,
+
+    /// Mount every drive read-only
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Write the program's changes to this directory instead of the drives, the host files
+    /// stay as they are
+    #[arg(long, value_name = "DIR")]
+    pub overlay: Option<PathBuf>,
+
+    /// Print the files the program created, modified or deleted when it exits
+    #[arg(long)]
+    pub report: bool,
+
+    /// Set an environment variable for the program, e.g. `--env PATH=C:\BIN`
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub env: Vec<(String, String)>,
+
+    /// Segment the program is loaded at, the PSP goes right before it
+    #[arg(long, default_value = "1000", value_parser = parse_load_segment)]
+    pub load_segment: u16,
+
+    /// Read the keyboard input from a file instead of stdin
+    #[arg(long, value_name = "FILE")]
+    pub input: Option<PathBuf>,
+
+    /// Key script for the BIOS keyboard, lines of hex scancode and ASCII like `1c 0d`
+    /// or `wait N` to wait N instructions
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+
+    /// Write the printer output (PRN, LPT1 and AH=05h) to a host file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    pub prn: Option<PathBuf>,
+
+    /// Write the AUX output (AUX and COM1) to a host file instead of stderr
+    #[arg(long, value_name = "FILE")]
+    pub aux: Option<PathBuf>,
+
+    /// Translate the program's console output from code page 437 to UTF-8
+    #[arg(long)]
+    pub cp437: bool,
+
+    /// Instructions the emulated cpu runs in a second, the timer and clock follow this rate
+    #[arg(long, default_value_t = DEFAULT_IPS)]
+    pub ips: u64,
+
+    /// Start the program's clock at this local time instead of following the host clock,
+    /// e.g. `--fixed-time 1991-06-01T12:00:00`
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub fixed_time: Option<NaiveDateTime>,
+
+    /// First of the exit codes when the emulator gives up or can't start the program:
+    /// CODE if it can't start, CODE+1 for an unimplemented function, CODE+2 for an
+    /// emulator fault and CODE+3 if it stopped before the program exited
+    #[arg(
+        long,
+        value_name = "CODE",
+        default_value_t = DEFAULT_FAILURE_CODE,
+        value_parser = clap::value_parser!(u8).range(..=Failure::MAX_BASE as i64)
+    )]
+    pub emulator_exit_code: u8,
+
+    /// Print the relocation fixups applied to the program and exit
+    #[arg(long)]
+    pub dump_relocations: bool,
+
     /// Path to executable MsDos EXE
     pub program_path: String,
+
+    /// Arguments passed to the program in the PSP command tail
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub program_args: Vec<String>,
 }
 
 impl CliArgs {
     pub fn debug_mode(&self) -> bool {
         self.debug || self.debug_file.is_some()
     }
+
+    /// Host directory of drive C:
+    pub fn c_drive(&self) -> PathBuf {
+        if let Some(dir) = &self.host_dir {
+            return dir.clone();
+        }
+
+        match Path::new(&self.program_path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.into(),
+            _ => PathBuf::from("."),
+        }
+    }
+}
+
+fn parse_drive(value: &str) -> Result<(u8, PathBuf), String> {
+    let (letter, dir) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected LETTER=DIR, got '{value}'"))?;
+
+    let mut chars = letter.chars();
+    let drive = match (chars.next().and_then(drive_number), chars.next()) {
+        (Some(drive), None) => drive,
+        _ => return Err(format!("invalid drive letter '{letter}'")),
+    };
+
+    Ok((drive, dir.into()))
+}
+
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{value}'"))?;
+    Ok((key.into(), value.into()))
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let value = value.trim_start_matches("0x");
+    u16::from_str_radix(value, 16).map_err(|err| format!("invalid hex value '{value}': {err}"))
+}
+
+/// The load segment has to leave room for the MCB and the PSP before the program and
+/// stay in conventional memory, if the program itself fits is checked when it is loaded
+fn parse_load_segment(value: &str) -> Result<u16, String> {
+    let segment = parse_hex(value)?;
+    let lowest = FIRST_MCB + 1 + 0x10;
+    if !(lowest..MEMORY_TOP).contains(&segment) {
+        return Err(format!(
+            "load segment {segment:04x} is outside of conventional memory {lowest:04x}-{:04x}",
+            MEMORY_TOP - 1
+        ));
+    }
+    Ok(segment)
 }
@@ -0,0 +1,170 @@
+use std::time::SystemTime;
+
+use chrono::{
+    DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike,
+};
+
+/// Years the DOS date functions and directory entries can store
+const FIRST_YEAR: i32 = 1980;
+const LAST_YEAR: i32 = 2107;
+
+/// Date and time the program sees, `--fixed-time` starts it at a given moment and moves
+/// it with the executed instructions so runs are reproducible
+pub struct Clock {
+    /// Time at the first instruction, the host local time is used when there is none
+    start: Option<NaiveDateTime>,
+    /// Instructions per emulated second
+    ips: u64,
+    /// Difference to the start or host time after the program has set the date or time
+    offset: TimeDelta,
+}
+
+impl Clock {
+    pub fn host(ips: u64) -> Self {
+        Self {
+            start: None,
+            ips: ips.max(1),
+            offset: TimeDelta::zero(),
+        }
+    }
+
+    pub fn fixed(start: NaiveDateTime, ips: u64) -> Self {
+        Self {
+            start: Some(start),
+            ..Self::host(ips)
+        }
+    }
+
+    /// Local date and time after running the instructions
+    pub fn now(&self, instructions: u64) -> NaiveDateTime {
+        let base = match self.start {
+            Some(start) => {
+                let micros = instructions as u128 * 1_000_000 / self.ips as u128;
+                start + TimeDelta::microseconds(micros as i64)
+            }
+            None => Local::now().naive_local(),
+        };
+        base + self.offset
+    }
+
+    /// Move the clock, the time keeps running from there
+    pub fn set(&mut self, time: NaiveDateTime, instructions: u64) {
+        self.offset += time - self.now(instructions);
+    }
+
+    /// Current time as a host timestamp for the files the program writes
+    pub fn system_time(&self, instructions: u64) -> SystemTime {
+        to_system_time(self.now(instructions))
+    }
+}
+
+/// Parse the `--fixed-time` value, e.g. `1991-06-01T12:00:00`
+pub fn parse_time(value: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .map_err(|err| format!("invalid time '{value}', expected YYYY-MM-DDTHH:MM:SS: {err}"))
+}
+
+/// Date from the registers of AH=2Bh, None if DOS can't represent it
+pub fn date(year: u16, month: u8, day: u8) -> Option<NaiveDate> {
+    let year = year as i32;
+    if !(FIRST_YEAR..=LAST_YEAR).contains(&year) {
+        return None;
+    }
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+}
+
+/// Time from the registers of AH=2Dh
+pub fn time(hour: u8, minute: u8, second: u8, hundredths: u8) -> Option<NaiveTime> {
+    if hundredths > 99 {
+        return None;
+    }
+    NaiveTime::from_hms_milli_opt(
+        hour as u32,
+        minute as u32,
+        second as u32,
+        hundredths as u32 * 10,
+    )
+}
+
+/// Date in the packed format of directory entries, years since 1980 in bits 9-15, month
+/// in bits 5-8 and the day in bits 0-4
+pub fn dos_date(date: NaiveDate) -> u16 {
+    let year = date.year().clamp(FIRST_YEAR, LAST_YEAR) - FIRST_YEAR;
+    ((year as u16) << 9) | ((date.month() as u16) << 5) | date.day() as u16
+}
+
+/// Time in the packed format of directory entries, hours in bits 11-15, minutes in bits
+/// 5-10 and seconds divided by two in bits 0-4
+pub fn dos_time(time: NaiveTime) -> u16 {
+    ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() / 2) as u16
+}
+
+/// Date and time from the packed directory entry format
+pub fn from_dos(date: u16, time: u16) -> Option<NaiveDateTime> {
+    let date = NaiveDate::from_ymd_opt(
+        FIRST_YEAR + (date >> 9) as i32,
+        ((date >> 5) & 0xf) as u32,
+        (date & 0x1f) as u32,
+    )?;
+    let time = NaiveTime::from_hms_opt(
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3f) as u32,
+        (time & 0x1f) as u32 * 2,
+    )?;
+    Some(date.and_time(time))
+}
+
+/// Local time of a host timestamp, e.g. the modification time of a file
+pub fn from_system_time(time: SystemTime) -> NaiveDateTime {
+    DateTime::<Local>::from(time).naive_local()
+}
+
+pub fn to_system_time(time: NaiveDateTime) -> SystemTime {
+    match Local.from_local_datetime(&time).earliest() {
+        Some(time) => time.into(),
+        // The time was skipped by a daylight saving change, use the same time in UTC
+        None => time.and_utc().into(),
+    }
+}
+
+/// Binary coded decimal used by the RTC functions of INT 1Ah
+pub fn to_bcd(value: u32) -> u8 {
+    ((((value / 10) % 10) << 4) | (value % 10)) as u8
+}
+
+pub fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0xf) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, dos_date, dos_time, from_dos, parse_time};
+
+    #[test]
+    fn fixed_clock() {
+        let start = parse_time("1991-06-01T12:00:00").unwrap();
+        let mut clock = Clock::fixed(start, 1000);
+        assert_eq!(clock.now(0), start);
+        assert_eq!(
+            clock.now(1500),
+            parse_time("1991-06-01T12:00:01").unwrap() + chrono::TimeDelta::milliseconds(500)
+        );
+
+        // Setting the time keeps the clock running from the new time
+        clock.set(parse_time("2000-01-01T00:00:00").unwrap(), 1500);
+        assert_eq!(clock.now(3500), parse_time("2000-01-01T00:00:02").unwrap());
+        assert!(parse_time("1991-06-01").is_err());
+    }
+
+    #[test]
+    fn packed_dates() {
+        let time = parse_time("1991-06-01T12:34:57").unwrap();
+        assert_eq!(dos_date(time.date()), (11 << 9) | (6 << 5) | 1);
+        assert_eq!(dos_time(time.time()), (12 << 11) | (34 << 5) | 28);
+        assert_eq!(
+            from_dos(dos_date(time.date()), dos_time(time.time())),
+            Some(parse_time("1991-06-01T12:34:56").unwrap())
+        );
+        assert_eq!(from_dos(0, 0), None);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+/// Returned by the input functions after the input has ended, same as Ctrl-Z
+pub const EOF: u8 = 0x1A;
+pub const CR: u8 = 0x0D;
+pub const LF: u8 = 0x0A;
+pub const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7F;
+const BELL: u8 = 0x07;
+
+/// How long to wait between polls when waiting for a key from the terminal
+const KEY_POLL: Duration = Duration::from_millis(10);
+
+enum Source {
+    /// Host stdin, read key by key when it is a terminal
+    Stdin { tty: bool },
+    /// Scripted input read from a file, everything is already in `pending`
+    Script,
+}
+
+/// Keyboard input for the DOS console functions (AH=01h-0Ch).
+///
+/// Line endings are translated to CR like the keyboard gives them, so
+/// both `\n` and `\r\n` in the input become a single CR.
+pub struct ConsoleInput {
+    source: Source,
+    pending: VecDeque<u8>,
+    /// Rest of a line read with AH=3Fh that didn't fit the caller's buffer
+    line: VecDeque<u8>,
+    /// No more input is coming, reads return Ctrl-Z
+    closed: bool,
+    /// Previous byte was CR, so LF right after it is dropped
+    last_cr: bool,
+    /// Terminal settings from `stty -g` while the terminal is in key by key mode
+    saved_tty: Option<String>,
+}
+
+impl ConsoleInput {
+    pub fn stdin() -> Self {
+        Self::new(Source::Stdin {
+            tty: io::stdin().is_terminal(),
+        })
+    }
+
+    /// Feed the input from a file instead of stdin, used for automated runs
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let mut input = Self::new(Source::Script);
+        for byte in fs::read(path)? {
+            input.push(byte);
+        }
+        input.closed = true;
+        Ok(input)
+    }
+
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            pending: VecDeque::new(),
+            line: VecDeque::new(),
+            closed: false,
+            last_cr: false,
+            saved_tty: None,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        let byte = match byte {
+            LF if self.last_cr => {
+                self.last_cr = false;
+                return;
+            }
+            LF => CR,
+            DEL => BACKSPACE,
+            byte => byte,
+        };
+        self.last_cr = byte == CR;
+        self.pending.push_back(byte);
+    }
+
+    /// Switch the terminal to non canonical mode without echo, so every key is
+    /// available right away and reads don't block
+    fn raw_mode(&mut self) {
+        if self.saved_tty.is_some() {
+            return;
+        }
+
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output();
+        let Ok(saved) = saved else {
+            return;
+        };
+        let status = Command::new("stty")
+            .args(["-icanon", "-echo", "min", "0", "time", "0"])
+            .stdin(Stdio::inherit())
+            .status();
+        if status.is_ok_and(|status| status.success()) {
+            self.saved_tty = Some(String::from_utf8_lossy(&saved.stdout).trim().to_string());
+        }
+    }
+
+    /// Give the terminal back in the mode it was in, e.g. when the debugger takes over
+    pub fn restore(&mut self) {
+        if let Some(saved) = self.saved_tty.take() {
+            let _ = Command::new("stty")
+                .arg(saved)
+                .stdin(Stdio::inherit())
+                .status();
+        }
+    }
+
+    /// Read what is available from stdin, blocks only if `wait` is set
+    fn fill(&mut self, wait: bool) {
+        let Source::Stdin { tty } = self.source else {
+            return;
+        };
+        if self.closed {
+            return;
+        }
+
+        if tty {
+            self.raw_mode();
+        }
+        let mut buf = [0u8; 64];
+        loop {
+            match io::stdin().read(&mut buf) {
+                Ok(0) if tty && wait => thread::sleep(KEY_POLL),
+                Ok(0) if tty => return,
+                Ok(0) | Err(_) => {
+                    self.closed = true;
+                    return;
+                }
+                Ok(count) => {
+                    for byte in &buf[..count] {
+                        self.push(*byte);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The input comes from a file or a pipe instead of the keyboard, like `PROG < FILE`
+    pub fn redirected(&self) -> bool {
+        !matches!(self.source, Source::Stdin { tty: true })
+    }
+
+    /// Is there a key waiting to be read (AH=0Bh)
+    pub fn available(&mut self) -> bool {
+        if self.pending.is_empty() {
+            // Pipes have no keys "waiting", so wait for the next byte to know if there is one
+            let wait = matches!(self.source, Source::Stdin { tty: false });
+            self.fill(wait);
+        }
+        !self.pending.is_empty()
+    }
+
+    /// Key without waiting, None if nothing has been typed (AH=06h with DL=FFh)
+    pub fn try_read(&mut self) -> Option<u8> {
+        if self.available() {
+            self.pending.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Wait for a key, returns Ctrl-Z once the input has ended
+    pub fn read(&mut self) -> u8 {
+        if self.pending.is_empty() {
+            self.fill(true);
+        }
+        self.pending.pop_front().unwrap_or(EOF)
+    }
+
+    /// Throw away keys typed ahead (AH=0Ch)
+    pub fn flush(&mut self) {
+        self.fill(false);
+        self.pending.clear();
+    }
+
+    /// Buffered line input with backspace editing (AH=0Ah).
+    ///
+    /// `max` includes the CR that ends the line, the returned line doesn't.
+    pub fn read_line(&mut self, max: u8) -> Vec<u8> {
+        self.edit_line(max).0
+    }
+
+    /// Line and whether it was ended with CR instead of the end of input
+    fn edit_line(&mut self, max: u8) -> (Vec<u8>, bool) {
+        let mut line = Vec::new();
+        if max == 0 {
+            return (line, false);
+        }
+
+        loop {
+            let key = self.read();
+            match key {
+                CR => {
+                    echo(&[CR]);
+                    return (line, true);
+                }
+                EOF if self.closed && self.pending.is_empty() => return (line, false),
+                BACKSPACE => {
+                    if line.pop().is_some() {
+                        echo(&[BACKSPACE, b' ', BACKSPACE]);
+                    }
+                }
+                key if line.len() + 1 < max as usize => {
+                    line.push(key);
+                    echo(&[key]);
+                }
+                _ => echo(&[BELL]),
+            }
+        }
+    }
+
+    /// Read from stdin handle (AH=3Fh), the console gives whole lines ending with CR LF
+    pub fn read_handle(&mut self, buf: &mut [u8]) -> usize {
+        if self.line.is_empty() {
+            let (line, ended) = self.edit_line(128);
+            self.line.extend(line);
+            if ended {
+                echo(&[LF]);
+                self.line.extend([CR, LF]);
+            }
+        }
+
+        let count = buf.len().min(self.line.len());
+        for (dst, src) in buf.iter_mut().zip(self.line.drain(..count)) {
+            *dst = src;
+        }
+        count
+    }
+}
+
+/// Characters 80h-FFh of code page 437
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Unicode character for a CP437 character, control characters and ASCII are kept as they are
+pub fn cp437_char(byte: u8) -> char {
+    match byte {
+        0x80.. => CP437_HIGH[byte as usize - 0x80],
+        byte => byte as char,
+    }
+}
+
+/// Convert CP437 text to UTF-8
+pub fn cp437_to_utf8(bytes: &[u8]) -> Vec<u8> {
+    let text: String = bytes.iter().map(|byte| cp437_char(*byte)).collect();
+    text.into_bytes()
+}
+
+/// Echo a character read from the keyboard
+fn echo(bytes: &[u8]) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(bytes);
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConsoleInput, EOF, Source, cp437_to_utf8};
+
+    #[test]
+    fn scripted_input() {
+        let mut input = ConsoleInput::new(Source::Script);
+        for byte in b"ab\r\ncd\n\x7f" {
+            input.push(*byte);
+        }
+        input.closed = true;
+
+        let mut keys = vec![];
+        while input.available() {
+            keys.push(input.read());
+        }
+        assert_eq!(keys, b"ab\rcd\r\x08");
+        assert_eq!(input.try_read(), None);
+        assert_eq!(input.read(), EOF);
+    }
+
+    #[test]
+    fn cp437_translation() {
+        assert_eq!(cp437_to_utf8(b"a\r\n"), b"a\r\n");
+        assert_eq!(
+            String::from_utf8(cp437_to_utf8(&[0xc9, 0xcd, 0xbb, 0x82, 0xff])).unwrap(),
+            "╔═╗é\u{a0}"
+        );
+    }
+
+    #[test]
+    fn handle_reads_lines() {
+        let mut input = ConsoleInput::new(Source::Script);
+        for byte in b"hello\x08p\nlast" {
+            input.push(*byte);
+        }
+        input.closed = true;
+
+        let mut buf = [0u8; 4];
+        assert_eq!(input.read_handle(&mut buf), 4);
+        assert_eq!(&buf, b"hell");
+        assert_eq!(input.read_handle(&mut buf), 3);
+        assert_eq!(&buf[..3], b"p\r\n");
+        assert_eq!(input.read_handle(&mut buf), 4);
+        assert_eq!(input.read_handle(&mut buf), 0);
+    }
+}
@@ -5,7 +5,10 @@ use std::{
     process::exit,
 };
 
-use crate::engine::{Engine, FarPointer};
+use crate::{
+    engine::{Engine, FarPointer},
+    video,
+};
 
 #[derive(Debug)]
 enum Command {
@@ -17,6 +20,12 @@ enum Command {
     Logon,
     Logoff,
     Break(String),
+    PortLogon,
+    PortLogoff,
+    PortBreak(String),
+    Overlays,
+    Processes,
+    Screen,
     WhileBreak { addr: u64, commands: Vec<Command> },
 }
 
@@ -78,12 +87,24 @@ impl Ast {
             (Command::Next(Some(count)), 1)
         } else if line == "c" || line == "continue" {
             (Command::Continue, 1)
+        } else if line == "o" || line == "overlays" {
+            (Command::Overlays, 1)
+        } else if line == "ps" || line == "processes" {
+            (Command::Processes, 1)
+        } else if line == "s" || line == "screen" {
+            (Command::Screen, 1)
         } else if line == "logon" {
             (Command::Logon, 1)
         } else if line == "logoff" {
             (Command::Logoff, 1)
         } else if line.starts_with("b ") || line.starts_with("break ") {
             (Command::Break(line.into()), 1)
+        } else if line == "portlogon" {
+            (Command::PortLogon, 1)
+        } else if line == "portlogoff" {
+            (Command::PortLogoff, 1)
+        } else if line.starts_with("pb ") || line.starts_with("portbreak ") {
+            (Command::PortBreak(line.into()), 1)
         } else if line.starts_with("while") {
             Self::parse_while(idx, lines)
         } else {
@@ -166,14 +187,14 @@ impl<'a> Debugger<'a> {
 
     fn run(&mut self) {
         if self.engine.exited() {
-            exit(0);
+            exit(self.engine.finish());
         }
         self.engine.start();
     }
 
     fn cont(&mut self) {
         if self.engine.exited() {
-            exit(0);
+            exit(self.engine.finish());
         }
 
         self.engine.cont();
@@ -181,7 +202,7 @@ impl<'a> Debugger<'a> {
 
     fn next(&mut self) {
         if self.engine.exited() {
-            exit(0);
+            exit(self.engine.finish());
         }
 
         self.engine.step();
@@ -200,6 +221,12 @@ impl<'a> Debugger<'a> {
         self.engine.add_break(addr);
     }
 
+    fn add_port_break(&mut self, cmd: &str) {
+        let port = cmd.split_whitespace().nth(1).unwrap();
+        let port = u16::from_str_radix(port, 16).unwrap();
+        self.engine.add_port_break(port);
+    }
+
     fn print(&self, cmd: &str) {
         let parts: Vec<&str> = cmd.split_whitespace().collect();
         let cpu = self.engine.read_cpu();
@@ -222,10 +249,36 @@ impl<'a> Debugger<'a> {
         println!("Data(u16) at {at}: {:x}", self.engine.read_mem(addr));
     }
 
+    fn print_processes(&self) {
+        let processes = self.engine.processes();
+        for (idx, process) in processes.iter().enumerate() {
+            let current = if idx + 1 == processes.len() { "*" } else { " " };
+            println!(
+                "{current} PSP {:04x}: {}",
+                process.psp_segment, process.path
+            );
+        }
+    }
+
+    fn print_overlays(&self) {
+        let overlays = self.engine.overlays();
+        if overlays.is_empty() {
+            println!("No overlays loaded");
+        }
+
+        for overlay in overlays {
+            let end = overlay.segment as u64 * 16 + overlay.size as u64;
+            println!(
+                "{} (overlay {}): {:04x}:0000 - {:05x}",
+                overlay.path, overlay.number, overlay.segment, end
+            );
+        }
+    }
+
     fn run_commands(&mut self, commands: &[Command]) {
         for command in commands {
             match command {
-                Command::Quit => exit(0),
+                Command::Quit => exit(self.engine.finish()),
                 Command::Print(cmd) => self.print(cmd),
                 Command::Run => self.run(),
                 Command::Next(None) => self.next(),
@@ -238,6 +291,12 @@ impl<'a> Debugger<'a> {
                 Command::Logon => self.engine.set_verbose(true),
                 Command::Logoff => self.engine.set_verbose(false),
                 Command::Break(cmd) => self.add_break(cmd),
+                Command::PortLogon => self.engine.set_port_log(true),
+                Command::PortLogoff => self.engine.set_port_log(false),
+                Command::PortBreak(cmd) => self.add_port_break(cmd),
+                Command::Overlays => self.print_overlays(),
+                Command::Processes => self.print_processes(),
+                Command::Screen => print!("{}", video::render(self.engine.engine())),
                 Command::WhileBreak { addr, commands } => {
                     self.engine.add_while_break(*addr);
                     loop {
@@ -0,0 +1,147 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Timelike};
+
+use crate::files::DosError;
+
+/// Character devices that can be opened by name like files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharDevice {
+    /// Throws away the output and has no input
+    Nul,
+    /// Keyboard and screen
+    Con,
+    /// Printer, PRN or LPT1
+    Printer,
+    /// Serial port, AUX or COM1
+    Aux,
+    /// CLOCK$ reads and sets the date and time as a 6 byte record
+    Clock,
+}
+
+/// Device a file name refers to. The names are reserved in every directory and with
+/// any extension, so `C:\TMP\NUL.TXT` is NUL too.
+pub fn parse_name(path: &str) -> Option<CharDevice> {
+    let name = path.rsplit(['\\', '/', ':']).next()?;
+    let base = name.split('.').next()?.trim_end().to_ascii_uppercase();
+    let device = match base.as_str() {
+        "NUL" => CharDevice::Nul,
+        "CON" => CharDevice::Con,
+        "PRN" | "LPT1" => CharDevice::Printer,
+        "AUX" | "COM1" => CharDevice::Aux,
+        "CLOCK$" => CharDevice::Clock,
+        _ => return None,
+    };
+    Some(device)
+}
+
+/// First day of the CLOCK$ day count
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1980, 1, 1).unwrap()
+}
+
+/// CLOCK$ record of the time: days since 1980 as a word, then minutes, hours,
+/// hundredths and seconds
+pub fn clock_record(time: NaiveDateTime) -> [u8; 6] {
+    let days = (time.date() - epoch()).num_days().clamp(0, u16::MAX as i64) as u16;
+    let [low, high] = days.to_le_bytes();
+    [
+        low,
+        high,
+        time.minute() as u8,
+        time.hour() as u8,
+        (time.nanosecond() / 10_000_000).min(99) as u8,
+        time.second() as u8,
+    ]
+}
+
+/// Time written to CLOCK$, None if the record isn't a valid time
+pub fn from_clock_record(record: &[u8]) -> Option<NaiveDateTime> {
+    let [low, high, minute, hour, hundredths, second] = record.try_into().ok()?;
+    let days = u16::from_le_bytes([low, high]);
+    let date = epoch() + TimeDelta::days(days as i64);
+    let time = NaiveTime::from_hms_milli_opt(
+        hour as u32,
+        minute as u32,
+        second as u32,
+        hundredths as u32 * 10,
+    )?;
+    Some(date.and_time(time))
+}
+
+/// Where the printer and AUX output goes, `--prn` and `--aux` capture it to host files
+pub struct Devices {
+    printer: Option<File>,
+    aux: Option<File>,
+}
+
+impl Devices {
+    pub fn new() -> Self {
+        Self {
+            printer: None,
+            aux: None,
+        }
+    }
+
+    pub fn capture_printer(&mut self, path: &Path) -> io::Result<()> {
+        self.printer = Some(File::create(path)?);
+        Ok(())
+    }
+
+    pub fn capture_aux(&mut self, path: &Path) -> io::Result<()> {
+        self.aux = Some(File::create(path)?);
+        Ok(())
+    }
+
+    /// Write printer or AUX output. Without a capture file the printer output goes to
+    /// stdout and AUX to stderr where they are most visible.
+    pub fn write(&mut self, device: CharDevice, buf: &[u8]) -> Result<usize, DosError> {
+        let capture = match device {
+            CharDevice::Printer => &mut self.printer,
+            CharDevice::Aux => &mut self.aux,
+            _ => return Ok(buf.len()),
+        };
+        match capture {
+            Some(file) => file.write_all(buf)?,
+            None if device == CharDevice::Printer => {
+                let mut stdout = io::stdout();
+                stdout.write_all(buf)?;
+                stdout.flush()?;
+            }
+            None => io::stderr().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CharDevice, clock_record, from_clock_record, parse_name};
+    use crate::clock::parse_time;
+
+    #[test]
+    fn device_names() {
+        assert_eq!(parse_name("NUL"), Some(CharDevice::Nul));
+        assert_eq!(parse_name("c:\\tmp\\nul.txt"), Some(CharDevice::Nul));
+        assert_eq!(parse_name("C:CON"), Some(CharDevice::Con));
+        assert_eq!(parse_name("lpt1"), Some(CharDevice::Printer));
+        assert_eq!(parse_name("AUX."), Some(CharDevice::Aux));
+        assert_eq!(parse_name("CLOCK$"), Some(CharDevice::Clock));
+        assert_eq!(parse_name("NULL.TXT"), None);
+        assert_eq!(parse_name("CON\\FILE"), None);
+    }
+
+    #[test]
+    fn clock_records() {
+        let time = parse_time("1991-06-01T12:34:56").unwrap();
+        let record = clock_record(time);
+        assert_eq!(u16::from_le_bytes([record[0], record[1]]), 4169);
+        assert_eq!(record[2..], [34, 12, 0, 56]);
+        assert_eq!(from_clock_record(&record), Some(time));
+        assert_eq!(from_clock_record(&[0, 0, 60, 0, 0, 0]), None);
+    }
+}
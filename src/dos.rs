@@ -0,0 +1,797 @@
+use std::io::SeekFrom;
+
+use chrono::{Datelike, Timelike};
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    bios,
+    clock::{self, dos_date, dos_time, from_system_time},
+    console,
+    device::{self, CharDevice},
+    engine::{Cpu, EngineData, ExitStatus, Overlay},
+    files::{ARCHIVE, AccessMode, DosError, DosFile, HIDDEN, READ_ONLY, SYSTEM, StdStream},
+    find, ioctl,
+    memory::{self, AllocError},
+    process,
+    program::Program,
+    sandbox::Change,
+};
+
+/// Set or clear the carry flag, MsDos uses it to tell if a function failed
+pub fn set_carry(emu: &mut Unicorn<EngineData>, carry: bool) {
+    let eflags = emu.reg_read(RegisterX86::EFLAGS).unwrap();
+    let eflags = if carry { eflags | 1 } else { eflags & !1 };
+    emu.reg_write(RegisterX86::EFLAGS, eflags).unwrap();
+}
+
+/// Set or clear the zero flag, AH=06h uses it to tell if a key was available
+pub fn set_zero(emu: &mut Unicorn<EngineData>, zero: bool) {
+    let eflags = emu.reg_read(RegisterX86::EFLAGS).unwrap();
+    let eflags = if zero { eflags | 0x40 } else { eflags & !0x40 };
+    emu.reg_write(RegisterX86::EFLAGS, eflags).unwrap();
+}
+
+/// Report a failed function call with the error code in AX
+fn set_error(emu: &mut Unicorn<EngineData>, error: DosError) {
+    if emu.get_data().verbose {
+        println!("Dos function failed with {error:?}");
+    }
+    emu.reg_write(RegisterX86::AX, error.code() as u64).unwrap();
+    set_carry(emu, true);
+}
+
+/// Report a failed memory call, BX gets the largest block that is available
+fn set_alloc_error(emu: &mut Unicorn<EngineData>, error: AllocError) {
+    set_error(emu, error.error);
+    emu.reg_write(RegisterX86::BX, error.largest as u64)
+        .unwrap();
+}
+
+/// Read a null terminated string from memory, MsDos paths are at most 128 bytes
+pub fn read_asciiz(emu: &Unicorn<EngineData>, addr: u64) -> Result<Vec<u8>, DosError> {
+    let mut data = emu.mem_read_as_vec(addr, 128).unwrap();
+    let null = data
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or(DosError::PathNotFound)?;
+    data.truncate(null);
+    Ok(data)
+}
+
+/// Path from its bytes, the bytes from 80h are CP437 characters
+fn decode_path(path: &[u8]) -> String {
+    path.iter().map(|byte| console::cp437_char(*byte)).collect()
+}
+
+/// Read a null terminated path
+pub fn read_path(emu: &Unicorn<EngineData>, addr: u64) -> Result<String, DosError> {
+    read_asciiz(emu, addr).map(|path| decode_path(&path))
+}
+
+/// Path argument of a function, the function fails when the path isn't terminated
+fn path_arg(emu: &mut Unicorn<EngineData>, addr: u64) -> Option<String> {
+    read_path(emu, addr).map_err(|err| set_error(emu, err)).ok()
+}
+
+/// Create a file for AH=3Ch, 5Ah and 5Bh, `new` fails if the file exists already
+fn create_file(
+    emu: &mut Unicorn<EngineData>,
+    file_name: &str,
+    attributes: u16,
+    new: bool,
+) -> Result<u16, DosError> {
+    if emu.get_data().verbose {
+        println!("Creating file {file_name}");
+    }
+
+    let data = emu.get_data_mut();
+    // Creating a device opens it, the name is reserved in every directory
+    if let Some(device) = device::parse_name(file_name) {
+        return data.files.open_device(device);
+    }
+    let target = data.drives.writable(file_name, true)?;
+    let name = target.name.clone();
+    let handle = if new {
+        data.files.create_new(&target.host, name)?
+    } else {
+        data.files.create(&target.host, name)?
+    };
+    let change = if target.existed {
+        Change::Modified
+    } else {
+        Change::Created
+    };
+    data.drives.changes.record(target.name, change);
+    // New files are always archived, the handle can write even if the file is read-only
+    let attributes = attributes & (READ_ONLY | HIDDEN | SYSTEM);
+    if attributes != 0 {
+        let _ = data.attributes.set(&target.host, attributes | ARCHIVE);
+    }
+    Ok(handle)
+}
+
+/// Create a file with a unique name in the directory (AH=5Ah), returns the handle and the
+/// name that is appended to the directory
+fn create_temp(
+    emu: &mut Unicorn<EngineData>,
+    dir: &str,
+    attributes: u16,
+) -> Result<(u16, String), DosError> {
+    let separator = if dir.is_empty() || dir.ends_with(['\\', '/', ':']) {
+        ""
+    } else {
+        "\\"
+    };
+    // MsDos makes the name from the time, the instruction count is unique enough
+    let seed = emu.get_data().instructions as u32;
+    for attempt in 0..0x100 {
+        let name = format!("{separator}{:08X}", seed.wrapping_add(attempt));
+        match create_file(emu, &format!("{dir}{name}"), attributes, true) {
+            Err(DosError::FileExists) => continue,
+            result => return result.map(|handle| (handle, name)),
+        }
+    }
+    Err(DosError::AccessDenied)
+}
+
+/// Stop the emulation because the program did something we cannot handle
+pub fn abort(emu: &mut Unicorn<EngineData>, msg: &str) {
+    eprintln!("{msg}, exiting...");
+    emu.get_data_mut().exit = Some(ExitStatus::Unimplemented);
+    emu.emu_stop().unwrap();
+}
+
+/// Load an overlay (AH=4Bh AL=03h) at the segment given in the ES:BX parameter block.
+///
+/// The block has the load segment followed by the relocation factor, the overlay isn't
+/// given a PSP and it isn't run.
+fn load_overlay(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), DosError> {
+    let file_name = read_path(emu, cpu.ds * 16 + cpu.dx)?;
+    let mut block = [0u8; 4];
+    emu.mem_read(cpu.es * 16 + cpu.bx, &mut block).unwrap();
+    let segment = u16::from_le_bytes([block[0], block[1]]);
+    let factor = u16::from_le_bytes([block[2], block[3]]);
+
+    let path = emu.get_data().drives.resolve(&file_name)?;
+    let overlay = Program::new(&path, factor as u64)?;
+
+    let addr = segment as u64 * 16;
+    let size = overlay.data().len();
+    emu.mem_write(addr, overlay.data()).unwrap();
+    // The overlay can replace code that is already translated
+    emu.ctl_remove_cache(addr, addr + size as u64).unwrap();
+
+    if emu.get_data().verbose {
+        println!("Loaded overlay {file_name} at {segment:04x}:0000, {size:x} bytes");
+    }
+
+    let overlays = &mut emu.get_data_mut().overlays;
+    overlays.retain(|o| o.segment != segment);
+    overlays.push(Overlay {
+        path: file_name,
+        segment,
+        size,
+        number: overlay.overlay_number(),
+    });
+    Ok(())
+}
+
+/// Program terminate (INT 20h), used by .COM programs and by RET to the PSP
+pub fn int20(emu: &mut Unicorn<EngineData>) {
+    process::terminate(emu, 0);
+}
+
+/// Standard handles the character output functions write to
+const STDOUT: u16 = 1;
+const STDPRN: u16 = 4;
+
+/// Write to a file handle, console output is optionally translated from CP437 to UTF-8.
+///
+/// Returns the number of bytes the program wrote, not the translated length.
+fn write_handle(emu: &mut Unicorn<EngineData>, handle: u16, buf: &[u8]) -> Result<usize, DosError> {
+    let data = emu.get_data_mut();
+    let cp437 = data.cp437;
+    match data.files.get_mut(handle)? {
+        DosFile::Device {
+            device: device @ (CharDevice::Printer | CharDevice::Aux),
+            ..
+        } => data.devices.write(*device, buf),
+        DosFile::Device {
+            device: CharDevice::Clock,
+            ..
+        } => {
+            // A record that isn't a valid time is ignored like the BIOS ignores it
+            if let Some(time) = device::from_clock_record(buf) {
+                data.clock.set(time, data.instructions);
+            }
+            Ok(buf.len())
+        }
+        file @ (DosFile::Console { .. }
+        | DosFile::Device {
+            device: CharDevice::Con,
+            ..
+        }) if cp437 && !buf.is_empty() => {
+            file.write(&console::cp437_to_utf8(buf))?;
+            Ok(buf.len())
+        }
+        file => {
+            let written = file.write(buf)?;
+            if let Some(name) = file.name() {
+                data.drives.changes.record(name.into(), Change::Modified);
+            }
+            Ok(written)
+        }
+    }
+}
+
+/// Read a `$` terminated string for AH=09h, the `$` isn't included
+fn read_dollar_string(emu: &Unicorn<EngineData>, segment: u64, offset: u64) -> Vec<u8> {
+    let mut string = Vec::new();
+    for idx in 0..0x10000 {
+        // The offset wraps inside the segment like it does on a real cpu
+        let addr = segment * 16 + ((offset + idx) & 0xffff);
+        let byte = emu.mem_read_as_vec(addr, 1).unwrap()[0];
+        if byte == b'$' {
+            break;
+        }
+        string.push(byte);
+    }
+    string
+}
+
+/// Functions that read from the keyboard that AH=0Ch can call after flushing the input
+const CONSOLE_INPUT: [u64; 5] = [0x01, 0x06, 0x07, 0x08, 0x0a];
+
+/// Keyboard input functions, `function` is AH or AL when called through AH=0Ch
+fn console_input(emu: &mut Unicorn<EngineData>, function: u64, cpu: &Cpu) {
+    if function == 0x01 {
+        let key = emu.get_data_mut().console.read();
+        // The echo goes to standard output like AH=02h, redirected and translated the same way
+        let _ = write_handle(emu, STDOUT, &[key]);
+        emu.reg_write(RegisterX86::AL, key as u64).unwrap();
+    } else if function == 0x06 {
+        let dl = cpu.dx & 0xff;
+        if dl == 0xff {
+            let key = emu.get_data_mut().console.try_read();
+            emu.reg_write(RegisterX86::AL, key.unwrap_or(0) as u64)
+                .unwrap();
+            set_zero(emu, key.is_none());
+        } else {
+            let _ = write_handle(emu, STDOUT, &[dl as u8]);
+            emu.reg_write(RegisterX86::AL, dl).unwrap();
+        }
+    } else if function == 0x07 || function == 0x08 {
+        let key = emu.get_data_mut().console.read();
+        emu.reg_write(RegisterX86::AL, key as u64).unwrap();
+    } else if function == 0x0a {
+        // Buffer has the max length including CR, the length read and then the characters
+        let addr = cpu.ds * 16 + cpu.dx;
+        let max = emu.mem_read_as_vec(addr, 1).unwrap()[0];
+        if max == 0 {
+            return;
+        }
+        let mut line = emu.get_data_mut().console.read_line(max);
+        let count = line.len() as u8;
+        line.insert(0, count);
+        line.push(console::CR);
+        emu.mem_write(addr + 1, &line).unwrap();
+    }
+}
+
+/// Handle MsDos function calls (INT 21h)
+pub fn int21(emu: &mut Unicorn<EngineData>) {
+    let cpu = Cpu::read_engine(emu);
+    let ah = cpu.ax >> 8;
+    let al = cpu.ax & 0xff;
+    if ah == 0x00 {
+        process::terminate(emu, 0);
+    } else if CONSOLE_INPUT.contains(&ah) {
+        console_input(emu, ah, &cpu);
+    } else if ah == 0x02 || ah == 0x05 {
+        let dl = cpu.dx & 0xff;
+        let handle = if ah == 0x02 { STDOUT } else { STDPRN };
+        // Character output can't fail, so errors are ignored like MsDos does
+        let _ = write_handle(emu, handle, &[dl as u8]);
+        emu.reg_write(RegisterX86::AL, dl).unwrap();
+    } else if ah == 0x09 {
+        let string = read_dollar_string(emu, cpu.ds, cpu.dx);
+        let _ = write_handle(emu, STDOUT, &string);
+        emu.reg_write(RegisterX86::AL, b'$' as u64).unwrap();
+    } else if ah == 0x0b {
+        let available = emu.get_data_mut().console.available();
+        let status = if available { 0xff } else { 0x00 };
+        emu.reg_write(RegisterX86::AL, status).unwrap();
+    } else if ah == 0x0c {
+        emu.get_data_mut().console.flush();
+        if CONSOLE_INPUT.contains(&al) {
+            console_input(emu, al, &cpu);
+        }
+    } else if ah == 0x0e {
+        let drives = &mut emu.get_data_mut().drives;
+        drives.set_current(cpu.dx as u8);
+        let last_drive = drives.last_drive();
+        emu.reg_write(RegisterX86::AL, last_drive as u64).unwrap();
+    } else if ah == 0x19 {
+        let current = emu.get_data().drives.current();
+        emu.reg_write(RegisterX86::AL, current as u64).unwrap();
+    } else if ah == 0x1a {
+        find::set_dta(emu, &cpu);
+    } else if ah == 0x25 {
+        // The vector is stored as offset followed by segment
+        let handler_ptr = ((cpu.ds as u32) << 16) | cpu.dx as u32;
+        emu.mem_write(al * 4, &handler_ptr.to_le_bytes()).unwrap();
+    } else if ah == 0x2a {
+        let instructions = emu.get_data().instructions;
+        let date = emu.get_data().clock.now(instructions).date();
+        emu.reg_write(RegisterX86::CX, date.year() as u64).unwrap();
+        emu.reg_write(RegisterX86::DH, date.month() as u64).unwrap();
+        emu.reg_write(RegisterX86::DL, date.day() as u64).unwrap();
+        emu.reg_write(
+            RegisterX86::AL,
+            date.weekday().num_days_from_sunday() as u64,
+        )
+        .unwrap();
+    } else if ah == 0x2b {
+        let [dl, dh] = (cpu.dx as u16).to_le_bytes();
+        let instructions = emu.get_data().instructions;
+        let clock = &mut emu.get_data_mut().clock;
+        let date = clock::date(cpu.cx as u16, dh, dl);
+        if let Some(date) = date {
+            let time = clock.now(instructions).time();
+            clock.set(date.and_time(time), instructions);
+        }
+        // AL is 0 when the date was set and FFh when it was invalid
+        let status = if date.is_some() { 0x00 } else { 0xff };
+        emu.reg_write(RegisterX86::AL, status).unwrap();
+    } else if ah == 0x2c {
+        let instructions = emu.get_data().instructions;
+        let time = emu.get_data().clock.now(instructions).time();
+        let hundredths = (time.nanosecond() / 10_000_000).min(99);
+        emu.reg_write(RegisterX86::CH, time.hour() as u64).unwrap();
+        emu.reg_write(RegisterX86::CL, time.minute() as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DH, time.second() as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DL, hundredths as u64).unwrap();
+    } else if ah == 0x2d {
+        let [cl, ch] = (cpu.cx as u16).to_le_bytes();
+        let [dl, dh] = (cpu.dx as u16).to_le_bytes();
+        let instructions = emu.get_data().instructions;
+        let clock = &mut emu.get_data_mut().clock;
+        let time = clock::time(ch, cl, dh, dl);
+        if let Some(time) = time {
+            let date = clock.now(instructions).date();
+            clock.set(date.and_time(time), instructions);
+        }
+        let status = if time.is_some() { 0x00 } else { 0xff };
+        emu.reg_write(RegisterX86::AL, status).unwrap();
+    } else if ah == 0x2f {
+        find::get_dta(emu);
+    } else if ah == 0x38 {
+        if emu.get_data().verbose {
+            println!("IGNORING COUNTRY DEPENDENT INFORMATION!!!!!")
+        }
+    } else if ah == 0x30 {
+        // TXLIST.EXE is checking for DOS version 2 so lets set the dos version to that for now
+        emu.reg_write(RegisterX86::AL, 2).unwrap();
+    } else if ah == 0x35 {
+        let (segment, offset) = bios::vector(emu, al as u8);
+        emu.reg_write(RegisterX86::BX, offset as u64).unwrap();
+        emu.reg_write(RegisterX86::ES, segment as u64).unwrap();
+    } else if ah == 0x39 {
+        let Some(path) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        match emu.get_data_mut().drives.mkdir(&path) {
+            Ok(()) => set_carry(emu, false),
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x3a {
+        let Some(path) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        let data = emu.get_data_mut();
+        match data.drives.rmdir(&path) {
+            Ok(host) => {
+                data.attributes.moved(&host, None);
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x3b {
+        let Some(path) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        match emu.get_data_mut().drives.chdir(&path) {
+            Ok(()) => set_carry(emu, false),
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x3c || ah == 0x5b {
+        // AH=5Bh fails if the file exists already
+        let Some(file_name) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        match create_file(emu, &file_name, cpu.cx as u16, ah == 0x5b) {
+            Ok(handle) => {
+                emu.reg_write(RegisterX86::AX, handle as u64).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x3d {
+        let Some(file_name) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        if emu.get_data().verbose {
+            println!("Opening file {file_name}");
+        }
+
+        let data = emu.get_data_mut();
+        let handle = AccessMode::from_al(al as u8).and_then(|mode| {
+            if let Some(device) = device::parse_name(&file_name) {
+                return data.files.open_device(device);
+            }
+            // Only handles that can write get a copy in the overlay
+            if mode.can_write() {
+                let target = data.drives.writable(&file_name, false)?;
+                return data.files.open(&target.host, mode, target.name, false);
+            }
+            let path = data.drives.parse(&file_name)?;
+            let host = data.drives.host_path(&path)?;
+            let protected = data.drives.sandboxed(path.drive);
+            data.files
+                .open(&host, mode, data.drives.dos_name(&path), protected)
+        });
+        match handle {
+            Ok(handle) => {
+                emu.reg_write(RegisterX86::AX, handle as u64).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x3e {
+        let data = emu.get_data_mut();
+        let now = data.clock.system_time(data.instructions);
+        match data.files.close(cpu.bx as u16, now) {
+            Ok(()) => set_carry(emu, false),
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x3f {
+        let mut buf = vec![0; cpu.cx as usize];
+        let data = emu.get_data_mut();
+        let read = match data.files.get_mut(cpu.bx as u16) {
+            // Keyboard input is line based and comes from the same place as AH=01h-0Ch
+            Ok(
+                DosFile::Console {
+                    stream: StdStream::Stdin,
+                    ..
+                }
+                | DosFile::Device {
+                    device: CharDevice::Con,
+                    ..
+                },
+            ) => Ok(data.console.read_handle(&mut buf)),
+            Ok(DosFile::Device {
+                device: CharDevice::Clock,
+                ..
+            }) => {
+                let record = device::clock_record(data.clock.now(data.instructions));
+                let count = buf.len().min(record.len());
+                buf[..count].copy_from_slice(&record[..count]);
+                Ok(count)
+            }
+            Ok(file) => file.read(&mut buf),
+            Err(err) => Err(err),
+        };
+        match read {
+            Ok(count) => {
+                emu.mem_write(cpu.ds * 16 + cpu.dx, &buf[..count]).unwrap();
+                emu.reg_write(RegisterX86::AX, count as u64).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x40 {
+        let ds = cpu.ds;
+        let dx = cpu.dx;
+        let addr = ds * 16 + dx;
+        let data = emu.mem_read_as_vec(addr, cpu.cx as usize).unwrap();
+        let is_console = matches!(
+            emu.get_data_mut().files.get_mut(cpu.bx as u16),
+            Ok(DosFile::Console { .. })
+        );
+        // On stderr so the diagnostic doesn't mix with the program's stdout
+        if is_console && emu.get_data().verbose {
+            eprintln!(
+                "Write to fd '{}', string: '{}'",
+                cpu.bx,
+                String::from_utf8_lossy(&data)
+            );
+        }
+
+        match write_handle(emu, cpu.bx as u16, &data) {
+            Ok(count) => {
+                emu.reg_write(RegisterX86::AX, count as u64).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x42 {
+        let offset = (cpu.cx << 16) | cpu.dx;
+        let pos = match al {
+            0 => Ok(SeekFrom::Start(offset)),
+            1 => Ok(SeekFrom::Current(offset as u32 as i32 as i64)),
+            2 => Ok(SeekFrom::End(offset as u32 as i32 as i64)),
+            _ => Err(DosError::InvalidFunction),
+        };
+        let new_pos = pos.and_then(|pos| {
+            emu.get_data_mut()
+                .files
+                .get_mut(cpu.bx as u16)
+                .and_then(|file| file.seek(pos))
+        });
+        match new_pos {
+            Ok(new_pos) => {
+                emu.reg_write(RegisterX86::DX, (new_pos >> 16) & 0xffff)
+                    .unwrap();
+                emu.reg_write(RegisterX86::AX, new_pos & 0xffff).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x41 {
+        let Some(file_name) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        if emu.get_data().verbose {
+            println!("Deleting file {file_name}");
+        }
+
+        let data = emu.get_data_mut();
+        match data.drives.unlink(&file_name) {
+            Ok(host) => {
+                data.attributes.moved(&host, None);
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x43 {
+        let Some(file_name) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        if emu.get_data().verbose {
+            println!("File attributes of {file_name}");
+        }
+
+        let data = emu.get_data_mut();
+        let attributes = match al {
+            0 => data
+                .drives
+                .resolve(&file_name)
+                .and_then(|path| data.attributes.get(&path))
+                .map(Some),
+            1 => data.drives.writable(&file_name, false).and_then(|target| {
+                data.attributes.set(&target.host, cpu.cx as u16)?;
+                data.drives.changes.record(target.name, Change::Modified);
+                Ok(None)
+            }),
+            _ => Err(DosError::InvalidFunction),
+        };
+        match attributes {
+            Ok(attributes) => {
+                if let Some(attributes) = attributes {
+                    emu.reg_write(RegisterX86::CX, attributes as u64).unwrap();
+                }
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x44 {
+        match ioctl::ioctl(emu, &cpu) {
+            Ok(()) => set_carry(emu, false),
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x47 {
+        let dst_addr = cpu.ds * 16 + cpu.si;
+        // DL 0 is the default drive, 1 = A: and so on
+        let drives = &emu.get_data().drives;
+        let drive = match cpu.dx & 0xff {
+            0 => drives.current(),
+            drive => drive as u8 - 1,
+        };
+        match drives.drive(drive).map(|drive| drive.cwd()) {
+            Ok(cwd) => {
+                // ASCIIZ string make sure it's null terminated
+                let mut path = cwd.into_bytes();
+                path.push(0);
+                emu.mem_write(dst_addr, &path).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x48 {
+        if !memory::chain_intact(emu) {
+            set_error(emu, DosError::MemoryControlBlocksDestroyed);
+            return;
+        }
+
+        let data = emu.get_data_mut();
+        let owner = data.psp_segment;
+        match data.memory.allocate(cpu.bx as u16, owner) {
+            Ok(segment) => {
+                memory::write_chain(emu);
+                emu.reg_write(RegisterX86::AX, segment as u64).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_alloc_error(emu, err),
+        }
+    } else if ah == 0x49 {
+        if !memory::chain_intact(emu) {
+            set_error(emu, DosError::MemoryControlBlocksDestroyed);
+            return;
+        }
+
+        match emu.get_data_mut().memory.free(cpu.es as u16) {
+            Ok(()) => {
+                memory::write_chain(emu);
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x4a {
+        if !memory::chain_intact(emu) {
+            set_error(emu, DosError::MemoryControlBlocksDestroyed);
+            return;
+        }
+
+        match emu
+            .get_data_mut()
+            .memory
+            .resize(cpu.es as u16, cpu.bx as u16)
+        {
+            Ok(()) => {
+                memory::write_chain(emu);
+                set_carry(emu, false);
+            }
+            Err(err) => set_alloc_error(emu, err),
+        }
+    } else if ah == 0x4b {
+        let result = match al {
+            0 | 1 => Some(process::exec(emu, &cpu, al == 0)),
+            3 => Some(load_overlay(emu, &cpu)),
+            _ => None,
+        };
+        if let Some(result) = result {
+            match result {
+                Ok(()) => set_carry(emu, false),
+                Err(err) => set_error(emu, err),
+            }
+        } else {
+            abort(emu, &format!("Unimplemented EXEC function {al:x}"));
+        }
+    } else if ah == 0x4c {
+        process::terminate(emu, al as u8);
+    } else if ah == 0x4d {
+        // MsDos clears the return code once it is read, a second call returns 0
+        let code = emu.get_data_mut().return_code.take().unwrap_or(0);
+        emu.reg_write(RegisterX86::AX, code as u64).unwrap();
+        set_carry(emu, false);
+    } else if ah == 0x4e || ah == 0x4f {
+        let result = if ah == 0x4e {
+            find::find_first(emu, &cpu)
+        } else {
+            find::find_next(emu)
+        };
+        match result {
+            Ok(()) => {
+                // AX is cleared on success like MsDos does
+                emu.reg_write(RegisterX86::AX, 0).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x50 {
+        emu.get_data_mut().psp_segment = cpu.bx as u16;
+    } else if ah == 0x51 || ah == 0x62 {
+        let psp_segment = emu.get_data().psp_segment;
+        emu.reg_write(RegisterX86::BX, psp_segment as u64).unwrap();
+    } else if ah == 0x56 {
+        let Some(from) = path_arg(emu, cpu.ds * 16 + cpu.dx) else {
+            return;
+        };
+        let Some(to) = path_arg(emu, cpu.es * 16 + cpu.di) else {
+            return;
+        };
+        if emu.get_data().verbose {
+            println!("Renaming {from} to {to}");
+        }
+
+        let data = emu.get_data_mut();
+        match data.drives.rename(&from, &to) {
+            Ok((from, to)) => {
+                data.attributes.moved(&from, Some(&to));
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x57 {
+        let data = emu.get_data_mut();
+        let now = data.clock.now(data.instructions);
+        let time = data.files.get_mut(cpu.bx as u16).and_then(|file| match al {
+            // Character devices have the current time
+            0 => file
+                .modified()
+                .map(|time| Some(time.map_or(now, from_system_time))),
+            1 => {
+                // MsDos stores the values as they are, a time the host can't have is ignored
+                if let Some(time) = clock::from_dos(cpu.dx as u16, cpu.cx as u16) {
+                    file.set_modified(clock::to_system_time(time))?;
+                    if let Some(name) = file.name() {
+                        data.drives.changes.record(name.into(), Change::Modified);
+                    }
+                }
+                Ok(None)
+            }
+            _ => Err(DosError::InvalidFunction),
+        });
+        match time {
+            Ok(time) => {
+                if let Some(time) = time {
+                    emu.reg_write(RegisterX86::CX, dos_time(time.time()) as u64)
+                        .unwrap();
+                    emu.reg_write(RegisterX86::DX, dos_date(time.date()) as u64)
+                        .unwrap();
+                }
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else if ah == 0x5a {
+        // The directory ends with a backslash and the name is appended to it
+        let addr = cpu.ds * 16 + cpu.dx;
+        let dir = match read_asciiz(emu, addr) {
+            Ok(dir) => dir,
+            Err(err) => {
+                set_error(emu, err);
+                return;
+            }
+        };
+        match create_temp(emu, &decode_path(&dir), cpu.cx as u16) {
+            Ok((handle, name)) => {
+                let mut name = name.into_bytes();
+                name.push(0);
+                emu.mem_write(addr + dir.len() as u64, &name).unwrap();
+                emu.reg_write(RegisterX86::AX, handle as u64).unwrap();
+                set_carry(emu, false);
+            }
+            Err(err) => set_error(emu, err),
+        }
+    } else {
+        abort(emu, &format!("Unimplemented ah for 0x21: 0x{ah:x}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use unicorn_engine::RegisterX86::AX;
+
+    use super::int21;
+    use crate::testing::{carry, emulator, set_regs};
+
+    #[test]
+    fn return_code_is_read_once() {
+        let mut emu = emulator(&env::temp_dir());
+        emu.get_data_mut().return_code = Some(0x0003);
+
+        set_regs(&mut emu, &[(AX, 0x4D00)]);
+        int21(&mut emu);
+        assert!(!carry(&emu));
+        assert_eq!(emu.reg_read(AX).unwrap(), 0x0003);
+
+        set_regs(&mut emu, &[(AX, 0x4D00)]);
+        int21(&mut emu);
+        assert_eq!(emu.reg_read(AX).unwrap(), 0);
+    }
+}
@@ -0,0 +1,769 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    files::{DosError, read_only},
+    sandbox::{self, Change, Changes, Overlay, host_names},
+};
+
+/// Drive numbers are zero based, 0 = A:, 2 = C:
+pub const DRIVE_C: u8 = 2;
+
+/// Convert a drive letter to its drive number
+pub fn drive_number(letter: char) -> Option<u8> {
+    let letter = letter.to_ascii_uppercase();
+    letter.is_ascii_uppercase().then(|| letter as u8 - b'A')
+}
+
+pub fn drive_letter(drive: u8) -> char {
+    (b'A' + drive) as char
+}
+
+/// Characters MsDos accepts in 8.3 file names besides letters and digits
+const VALID_SPECIAL: &str = "!#$%&'()-@^_`{}~";
+
+fn valid_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || VALID_SPECIAL.contains(c)
+}
+
+/// Uppercase a name and truncate it to 8.3 the way MsDos does for names given by the program
+pub fn truncate_83(name: &str) -> String {
+    if name == "." || name == ".." {
+        return name.into();
+    }
+
+    let upper = name.to_ascii_uppercase();
+    let (base, ext) = upper.split_once('.').unwrap_or((&upper, ""));
+    let base: String = base.chars().take(8).collect();
+    let ext: String = ext.chars().take(3).collect();
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+/// The name is already a valid uppercase 8.3 name
+fn is_83(name: &str) -> bool {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    !base.is_empty()
+        && base.len() <= 8
+        && ext.len() <= 3
+        && base.chars().chain(ext.chars()).all(valid_char)
+        && !base
+            .chars()
+            .chain(ext.chars())
+            .any(|c| c.is_ascii_lowercase())
+}
+
+/// Build the 8.3 names for host file names in one directory.
+///
+/// Names that already fit are only uppercased, the rest get the Windows style
+/// `LONGNA~1.TXT` aliases numbered in sorted host name order. On a case sensitive host
+/// only the first of the names that differ in case keeps its name.
+pub fn short_names(host_names: &[String]) -> Vec<(String, String)> {
+    let mut sorted: Vec<&String> = host_names.iter().collect();
+    sorted.sort();
+
+    let mut taken: Vec<String> = sorted
+        .iter()
+        .map(|name| name.to_ascii_uppercase())
+        .filter(|name| is_83(name))
+        .collect();
+
+    let mut names = Vec::new();
+    for host in sorted {
+        let upper = host.to_ascii_uppercase();
+        if is_83(&upper) && !names.iter().any(|(name, _)| *name == upper) {
+            names.push((upper, host.clone()));
+            continue;
+        }
+
+        let clean = |s: &str| -> String { s.chars().filter(|c| valid_char(*c)).collect() };
+        let (base, ext) = match upper.rsplit_once('.') {
+            Some((base, ext)) if !base.is_empty() => (clean(base), clean(ext)),
+            _ => (clean(&upper), String::new()),
+        };
+        let ext: String = ext.chars().take(3).collect();
+
+        let mut n = 1;
+        let alias = loop {
+            let tail = format!("~{n}");
+            let base: String = base.chars().take(8 - tail.len()).collect();
+            let alias = if ext.is_empty() {
+                format!("{base}{tail}")
+            } else {
+                format!("{base}{tail}.{ext}")
+            };
+            if !taken.contains(&alias) {
+                break alias;
+            }
+            n += 1;
+        };
+        taken.push(alias.clone());
+        names.push((alias, host.clone()));
+    }
+
+    names
+}
+
+/// 8.3 names of every entry in a host directory, paired with the host names
+pub fn dir_entries(dir: &Path) -> Result<Vec<(String, String)>, DosError> {
+    let host_names: Vec<String> = fs::read_dir(dir)
+        .map_err(|_| DosError::PathNotFound)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    Ok(short_names(&host_names))
+}
+
+pub struct Drive {
+    /// Host directory that is the root of the drive
+    root: PathBuf,
+    /// Current directory as DOS path components, empty is the root
+    cwd: Vec<String>,
+    /// The program can read the files but not change them
+    read_only: bool,
+}
+
+impl Drive {
+    pub fn new(root: PathBuf, read_only: bool) -> Self {
+        Self {
+            root,
+            cwd: vec![],
+            read_only,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Current directory without the drive and leading backslash, like AH=47h returns it
+    pub fn cwd(&self) -> String {
+        self.cwd.join("\\")
+    }
+}
+
+/// Split DOS path into the drive and normalized components
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DosPath {
+    pub drive: u8,
+    pub components: Vec<String>,
+}
+
+/// Host path where a file can be changed, see [`VirtualDrives::writable`]
+pub struct WritePath {
+    pub host: PathBuf,
+    /// DOS path of the file for the change report
+    pub name: String,
+    /// The file was there before
+    pub existed: bool,
+}
+
+/// Drive letters mounted to host directories
+pub struct VirtualDrives {
+    drives: BTreeMap<u8, Drive>,
+    current: u8,
+    /// Changes go to the overlay instead of the drives when there is one
+    overlay: Option<Overlay>,
+    /// Files the program has created, modified or deleted
+    pub changes: Changes,
+}
+
+impl VirtualDrives {
+    pub fn new() -> Self {
+        Self {
+            drives: BTreeMap::new(),
+            current: DRIVE_C,
+            overlay: None,
+            changes: Changes::new(),
+        }
+    }
+
+    pub fn mount(&mut self, drive: u8, root: PathBuf, read_only: bool) {
+        self.drives.insert(drive, Drive::new(root, read_only));
+    }
+
+    /// Keep the program's changes in a copy-on-write directory, the drives aren't touched
+    pub fn set_overlay(&mut self, dir: PathBuf) -> io::Result<()> {
+        self.overlay = Some(Overlay::new(dir)?);
+        Ok(())
+    }
+
+    pub fn current(&self) -> u8 {
+        self.current
+    }
+
+    /// Select the current drive, returns false if the drive isn't mounted
+    pub fn set_current(&mut self, drive: u8) -> bool {
+        if self.drives.contains_key(&drive) {
+            self.current = drive;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of drive letters available, same as LASTDRIVE that is at least E:
+    pub fn last_drive(&self) -> u8 {
+        let highest = self.drives.keys().last().map_or(0, |d| d + 1);
+        highest.max(5)
+    }
+
+    pub fn drive(&self, drive: u8) -> Result<&Drive, DosError> {
+        self.drives.get(&drive).ok_or(DosError::InvalidDrive)
+    }
+
+    /// The host files of the drive can't be changed through a handle, they are read-only
+    /// or the changes go to the overlay
+    pub fn sandboxed(&self, drive: u8) -> bool {
+        self.overlay.is_some() || self.drive(drive).is_ok_and(|mount| mount.read_only)
+    }
+
+    /// Parse a DOS path, relative paths are resolved against the current directory of the drive
+    pub fn parse(&self, path: &str) -> Result<DosPath, DosError> {
+        let (drive, rest) = match path.as_bytes() {
+            [letter, b':', ..] => {
+                let drive = drive_number(*letter as char).ok_or(DosError::InvalidDrive)?;
+                (drive, &path[2..])
+            }
+            _ => (self.current, path),
+        };
+        let mount = self.drive(drive)?;
+
+        let mut components = if rest.starts_with(['\\', '/']) {
+            vec![]
+        } else {
+            mount.cwd.clone()
+        };
+
+        // `..` stops at the root of the drive like it does on MsDos
+        for part in rest.split(['\\', '/']).filter(|p| !p.is_empty()) {
+            match part {
+                "." => {}
+                ".." => {
+                    components.pop();
+                }
+                part => components.push(truncate_83(part)),
+            }
+        }
+
+        Ok(DosPath { drive, components })
+    }
+
+    /// DOS path like `C:\\TMP\\OUT.TXT` for the change report
+    pub fn dos_name(&self, path: &DosPath) -> String {
+        format!(
+            "{}:\\{}",
+            drive_letter(path.drive),
+            path.components.join("\\")
+        )
+    }
+
+    /// Find the host path for a DOS path.
+    ///
+    /// Every directory on the way has to exist, but the last component is allowed to be
+    /// missing so the result can be used to create files.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, DosError> {
+        let path = self.parse(path)?;
+        self.host_path(&path)
+    }
+
+    pub fn host_path(&self, path: &DosPath) -> Result<PathBuf, DosError> {
+        let relative = self.relative(path)?;
+        self.locate(path.drive, &relative)
+    }
+
+    /// 8.3 names and host paths of the entries in a directory
+    pub fn entries(&self, dir: &DosPath) -> Result<Vec<(String, PathBuf)>, DosError> {
+        let relative = self.relative(dir)?;
+        let entries = self.list(dir.drive, &relative)?;
+        // Symlinks that lead out of the drive are left out
+        Ok(entries
+            .into_iter()
+            .filter_map(|(dos, name)| {
+                let host = self.locate(dir.drive, &relative.join(name)).ok()?;
+                Some((dos, host))
+            })
+            .collect())
+    }
+
+    /// Host path relative to the drive root, like [`Self::host_path`] the last component
+    /// can be missing
+    fn relative(&self, path: &DosPath) -> Result<PathBuf, DosError> {
+        let mut relative = PathBuf::new();
+        let count = path.components.len();
+        for (idx, component) in path.components.iter().enumerate() {
+            let is_last = idx + 1 == count;
+            let found = self
+                .list(path.drive, &relative)?
+                .into_iter()
+                .find(|(dos, _)| dos == component);
+            match found {
+                Some((_, name)) => relative.push(name),
+                None if is_last => relative.push(component),
+                None => return Err(DosError::PathNotFound),
+            }
+        }
+
+        Ok(relative)
+    }
+
+    /// 8.3 names of a directory as the program sees it, the overlay's files are added and
+    /// the files deleted in it are left out
+    fn list(&self, drive: u8, relative: &Path) -> Result<Vec<(String, String)>, DosError> {
+        let lower = self.drive(drive)?.root.join(relative);
+        let mut found = false;
+        let mut names = vec![];
+        if lower.is_dir() {
+            self.confine(drive, lower.clone())?;
+            found = true;
+            names = host_names(&lower);
+        }
+
+        if let Some(overlay) = &self.overlay {
+            let upper = overlay.upper(drive, relative);
+            if upper.is_dir() {
+                found = true;
+                for name in host_names(&upper) {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            names.retain(|name| !overlay.is_deleted(drive, &relative.join(name)));
+        }
+
+        if !found {
+            return Err(DosError::PathNotFound);
+        }
+        Ok(short_names(&names))
+    }
+
+    /// Host path of a file on the drive or in the overlay if it has been changed there
+    fn locate(&self, drive: u8, relative: &Path) -> Result<PathBuf, DosError> {
+        let lower = self.drive(drive)?.root.join(relative);
+        let host = match &self.overlay {
+            Some(overlay) => {
+                let upper = overlay.upper(drive, relative);
+                if upper.exists() || overlay.is_deleted(drive, relative) {
+                    upper
+                } else {
+                    lower
+                }
+            }
+            None => lower,
+        };
+        self.confine(drive, host)
+    }
+
+    /// Symlinks on the host can't lead out of the drive or its overlay directory
+    fn confine(&self, drive: u8, host: PathBuf) -> Result<PathBuf, DosError> {
+        let mut roots = vec![self.drive(drive)?.root.clone()];
+        if let Some(overlay) = &self.overlay {
+            roots.push(overlay.upper(drive, Path::new("")));
+        }
+        sandbox::confine(host, &roots)
+    }
+
+    fn check_writable(&self, drive: u8) -> Result<(), DosError> {
+        if self.drive(drive)?.read_only {
+            return Err(DosError::AccessDenied);
+        }
+        Ok(())
+    }
+
+    /// Host path to change a file at. With an overlay the file is copied there first so
+    /// the original stays as it is. `create` allows the file to be missing.
+    pub fn writable(&mut self, path: &str, create: bool) -> Result<WritePath, DosError> {
+        let path = self.parse(path)?;
+        self.writable_path(&path, create)
+    }
+
+    fn writable_path(&mut self, path: &DosPath, create: bool) -> Result<WritePath, DosError> {
+        let relative = self.relative(path)?;
+        let current = self.locate(path.drive, &relative)?;
+        let existed = current.exists();
+        if !existed && !create {
+            return Err(DosError::FileNotFound);
+        }
+        self.check_writable(path.drive)?;
+
+        let name = self.dos_name(path);
+        let lower = self.drive(path.drive)?.root.join(&relative);
+        let Some(overlay) = &mut self.overlay else {
+            return Ok(WritePath {
+                host: current,
+                name,
+                existed,
+            });
+        };
+
+        let upper = overlay.upper(path.drive, &relative);
+        if let Some(parent) = upper.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if !existed {
+            overlay.restore(path.drive, &relative, &lower);
+        } else if !upper.exists() {
+            if current.is_dir() {
+                fs::create_dir(&upper)?;
+            } else {
+                sandbox::copy_file(&current, &upper)?;
+            }
+        }
+
+        Ok(WritePath {
+            host: self.confine(path.drive, upper)?,
+            name,
+            existed,
+        })
+    }
+
+    /// Remove a file or an empty directory, with an overlay the original is only hidden
+    fn remove(&mut self, drive: u8, relative: &Path, host: &Path) -> Result<(), DosError> {
+        let lower = self.drive(drive)?.root.join(relative);
+        let Some(overlay) = &mut self.overlay else {
+            let removed = if host.is_dir() {
+                fs::remove_dir(host)
+            } else {
+                fs::remove_file(host)
+            };
+            return removed.map_err(|_| DosError::AccessDenied);
+        };
+
+        let upper = overlay.upper(drive, relative);
+        if upper.is_dir() {
+            fs::remove_dir_all(&upper)?;
+        } else if upper.exists() {
+            fs::remove_file(&upper)?;
+        }
+        if lower.exists() {
+            overlay.delete(drive, relative);
+        }
+        Ok(())
+    }
+
+    /// Copy a file or a directory tree as the program sees it
+    fn copy_tree(&self, drive: u8, relative: &Path, to: &Path) -> Result<(), DosError> {
+        let from = self.locate(drive, relative)?;
+        if !from.is_dir() {
+            return Ok(sandbox::copy_file(&from, to)?);
+        }
+
+        fs::create_dir(to)?;
+        for (_, name) in self.list(drive, relative)? {
+            self.copy_tree(drive, &relative.join(&name), &to.join(&name))?;
+        }
+        Ok(())
+    }
+
+    /// Find the DOS path like `C:\\TOOLS\\PROG.EXE` of a host file on one of the drives
+    pub fn dos_path(&self, host: &Path) -> Option<String> {
+        let host = host.canonicalize().ok()?;
+        for (drive, mount) in &self.drives {
+            let Ok(root) = mount.root.canonicalize() else {
+                continue;
+            };
+            let Ok(relative) = host.strip_prefix(&root) else {
+                continue;
+            };
+
+            let mut dir = root.clone();
+            let mut components = Vec::new();
+            for part in relative.iter() {
+                let part = part.to_str()?;
+                let (dos, _) = dir_entries(&dir)
+                    .ok()?
+                    .into_iter()
+                    .find(|(_, name)| name == part)?;
+                components.push(dos);
+                dir.push(part);
+            }
+
+            return Some(format!(
+                "{}:\\{}",
+                drive_letter(*drive),
+                components.join("\\")
+            ));
+        }
+
+        None
+    }
+
+    /// Create a directory (AH=39h)
+    pub fn mkdir(&mut self, path: &str) -> Result<(), DosError> {
+        let path = self.parse(path)?;
+        let host = self.host_path(&path)?;
+        if path.components.is_empty() || host.exists() {
+            return Err(DosError::AccessDenied);
+        }
+        let target = self.writable_path(&path, true)?;
+        fs::create_dir(&target.host).map_err(|_| DosError::AccessDenied)?;
+        self.changes.record(target.name, Change::Created);
+        Ok(())
+    }
+
+    /// Remove an empty directory (AH=3Ah), returns the host path that was removed
+    pub fn rmdir(&mut self, path: &str) -> Result<PathBuf, DosError> {
+        let path = self.parse(path)?;
+        let relative = self.relative(&path)?;
+        let host = self.locate(path.drive, &relative)?;
+        if !host.is_dir() {
+            return Err(DosError::PathNotFound);
+        }
+        if path.components.is_empty() {
+            return Err(DosError::AccessDenied);
+        }
+        // The current directory and the directories above it can't be removed
+        if self.drive(path.drive)?.cwd.starts_with(&path.components) {
+            return Err(DosError::RemoveCurrentDirectory);
+        }
+        // The directory isn't empty
+        if !self.list(path.drive, &relative)?.is_empty() {
+            return Err(DosError::AccessDenied);
+        }
+        self.check_writable(path.drive)?;
+        self.remove(path.drive, &relative, &host)?;
+        self.changes.record(self.dos_name(&path), Change::Deleted);
+        Ok(host)
+    }
+
+    /// Delete a file (AH=41h), returns the host path that was deleted
+    pub fn unlink(&mut self, path: &str) -> Result<PathBuf, DosError> {
+        let path = self.parse(path)?;
+        let relative = self.relative(&path)?;
+        let host = self.locate(path.drive, &relative)?;
+        // Directories aren't files for AH=41h
+        let meta = fs::metadata(&host).map_err(|_| DosError::FileNotFound)?;
+        if meta.is_dir() {
+            return Err(DosError::FileNotFound);
+        }
+        // The host would delete read-only files, MsDos doesn't
+        if read_only(&meta.permissions()) {
+            return Err(DosError::AccessDenied);
+        }
+        self.check_writable(path.drive)?;
+        self.remove(path.drive, &relative, &host)?;
+        self.changes.record(self.dos_name(&path), Change::Deleted);
+        Ok(host)
+    }
+
+    /// Rename or move a file or a directory on the same drive (AH=56h), returns the old
+    /// and the new host path
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(PathBuf, PathBuf), DosError> {
+        let from = self.parse(from)?;
+        let to = self.parse(to)?;
+        if from.drive != to.drive {
+            return Err(DosError::NotSameDevice);
+        }
+
+        let from_relative = self.relative(&from)?;
+        let from_host = self.locate(from.drive, &from_relative)?;
+        if !from_host.exists() {
+            return Err(DosError::FileNotFound);
+        }
+        let to_host = self.host_path(&to)?;
+        // The host would replace an existing file, and a directory can't go inside itself
+        if to_host.exists()
+            || to.components.is_empty()
+            || to.components.starts_with(&from.components)
+        {
+            return Err(DosError::AccessDenied);
+        }
+        self.check_writable(from.drive)?;
+
+        let to_host = if self.overlay.is_some() {
+            let target = self.writable_path(&to, true)?;
+            self.copy_tree(from.drive, &from_relative, &target.host)?;
+            self.remove(from.drive, &from_relative, &from_host)?;
+            target.host
+        } else {
+            fs::rename(&from_host, &to_host).map_err(|_| DosError::AccessDenied)?;
+            to_host
+        };
+        self.changes.record(self.dos_name(&from), Change::Deleted);
+        self.changes.record(self.dos_name(&to), Change::Created);
+        Ok((from_host, to_host))
+    }
+
+    /// Change the current directory of the drive given in the path (AH=3Bh)
+    pub fn chdir(&mut self, path: &str) -> Result<(), DosError> {
+        let path = self.parse(path)?;
+        let host = self.host_path(&path)?;
+        if !host.is_dir() {
+            return Err(DosError::PathNotFound);
+        }
+
+        let drive = self
+            .drives
+            .get_mut(&path.drive)
+            .ok_or(DosError::InvalidDrive)?;
+        drive.cwd = path.components;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{DRIVE_C, VirtualDrives, short_names, truncate_83};
+    use crate::{
+        files::{ARCHIVE, Attributes, DosError, HIDDEN, READ_ONLY},
+        testing::TempDir,
+    };
+
+    #[test]
+    fn short_name_aliases() {
+        let names: Vec<String> = [
+            "readme.txt",
+            "README.TXT",
+            "LongFileName.text",
+            "longfile1.txt",
+            "longfile2.txt",
+            "a.b.c",
+        ]
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+        let short = short_names(&names);
+
+        assert!(short.contains(&("README.TXT".into(), "README.TXT".into())));
+        assert!(short.contains(&("README~1.TXT".into(), "readme.txt".into())));
+        assert!(short.contains(&("LONGFI~1.TEX".into(), "LongFileName.text".into())));
+        assert!(short.contains(&("LONGFI~1.TXT".into(), "longfile1.txt".into())));
+        assert!(short.contains(&("LONGFI~2.TXT".into(), "longfile2.txt".into())));
+        assert!(short.contains(&("AB~1.C".into(), "a.b.c".into())));
+    }
+
+    #[test]
+    fn truncate_program_names() {
+        assert_eq!(truncate_83("config.sys"), "CONFIG.SYS");
+        assert_eq!(truncate_83("verylongname.text"), "VERYLONG.TEX");
+        assert_eq!(truncate_83(".."), "..");
+    }
+
+    #[test]
+    fn file_management() {
+        let dir = TempDir::new("drive-test");
+        let root = dir.0.clone();
+        let mut drives = VirtualDrives::new();
+        drives.mount(DRIVE_C, root.clone(), false);
+
+        drives.mkdir("C:\\TMP").unwrap();
+        assert_eq!(drives.mkdir("tmp"), Err(DosError::AccessDenied));
+        assert_eq!(drives.mkdir("NODIR\\SUB"), Err(DosError::PathNotFound));
+
+        fs::write(root.join("TMP").join("a.txt"), b"data").unwrap();
+        assert_eq!(
+            drives.rename("tmp\\a.txt", "D:\\B.TXT"),
+            Err(DosError::InvalidDrive)
+        );
+        drives.rename("tmp\\a.txt", "\\b.txt").unwrap();
+        assert!(root.join("B.TXT").exists());
+        assert_eq!(drives.unlink("TMP\\A.TXT"), Err(DosError::FileNotFound));
+        assert_eq!(drives.unlink("TMP"), Err(DosError::FileNotFound));
+
+        drives.chdir("TMP").unwrap();
+        assert_eq!(drives.rmdir("\\TMP"), Err(DosError::RemoveCurrentDirectory));
+        drives.chdir("..").unwrap();
+        drives.rmdir("TMP").unwrap();
+
+        // Only the owner's write permission follows the read-only attribute
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let file = root.join("B.TXT");
+            let mode = || fs::metadata(&file).unwrap().permissions().mode() & 0o777;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+            let mut attributes = Attributes::new();
+            attributes.set(&file, HIDDEN | ARCHIVE).unwrap();
+            assert_eq!(mode(), 0o644);
+            attributes.set(&file, READ_ONLY | ARCHIVE).unwrap();
+            assert_eq!(mode(), 0o444);
+            assert_eq!(drives.unlink("B.TXT"), Err(DosError::AccessDenied));
+            attributes.set(&file, ARCHIVE).unwrap();
+            assert_eq!(mode(), 0o644);
+        }
+        drives.unlink("B.TXT").unwrap();
+        assert!(fs::read_dir(&root).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn sandbox_policy() {
+        let base = TempDir::new("sandbox-test");
+        let root = base.0.join("drive");
+        let overlay = base.0.join("overlay");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("keep.txt"), b"original").unwrap();
+        fs::write(root.join("sub").join("old.txt"), b"old").unwrap();
+
+        let mut drives = VirtualDrives::new();
+        drives.mount(DRIVE_C, root.clone(), true);
+        assert_eq!(
+            drives.writable("KEEP.TXT", false).err(),
+            Some(DosError::AccessDenied)
+        );
+        assert_eq!(drives.unlink("KEEP.TXT"), Err(DosError::AccessDenied));
+        // `..` stops at the root of the drive
+        assert_eq!(
+            drives.resolve("..\\..\\KEEP.TXT"),
+            Ok(root.join("keep.txt"))
+        );
+
+        drives.mount(DRIVE_C, root.clone(), false);
+        drives.set_overlay(overlay.clone()).unwrap();
+        let target = drives.writable("KEEP.TXT", false).unwrap();
+        fs::write(&target.host, b"changed").unwrap();
+        assert_eq!(fs::read(root.join("keep.txt")).unwrap(), b"original");
+        assert_eq!(drives.resolve("KEEP.TXT"), Ok(target.host));
+
+        drives.unlink("SUB\\OLD.TXT").unwrap();
+        assert!(root.join("sub").join("old.txt").exists());
+        assert_eq!(
+            drives.resolve("SUB\\OLD.TXT").map(|p| p.exists()),
+            Ok(false)
+        );
+        drives.rmdir("SUB").unwrap();
+        drives.mkdir("SUB").unwrap();
+        drives.rename("KEEP.TXT", "SUB\\NEW.TXT").unwrap();
+        let names: Vec<String> = drives
+            .entries(&drives.parse("\\SUB").unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["NEW.TXT"]);
+        assert!(root.join("keep.txt").exists());
+        assert_eq!(
+            drives.changes.report(),
+            "Files changed by the program:\n    \
+             deleted  C:\\KEEP.TXT\n    \
+             modified C:\\SUB\n    \
+             created  C:\\SUB\\NEW.TXT\n    \
+             deleted  C:\\SUB\\OLD.TXT\n"
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(env::temp_dir(), root.join("escape")).unwrap();
+            assert_eq!(drives.resolve("ESCAPE\\X"), Err(DosError::AccessDenied));
+
+            // Creating the file would follow the link out of the drive
+            let outside = base.0.join("outside.txt");
+            std::os::unix::fs::symlink(&outside, root.join("dangling")).unwrap();
+            assert_eq!(drives.resolve("DANGLING"), Err(DosError::AccessDenied));
+            assert_eq!(
+                drives.writable("DANGLING", true).err(),
+                Some(DosError::AccessDenied)
+            );
+            assert!(!outside.exists());
+        }
+    }
+}
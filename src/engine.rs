@@ -1,28 +1,50 @@
-use crate::program::{PSP, Program};
-use std::{collections::HashMap, fmt::Display, rc::Rc};
-use unicorn_engine::{Arch, Mode, Prot, RegisterX86, Unicorn};
+use crate::{
+    bios::{self, INTERRUPT_FLAG},
+    cli::CliArgs,
+    clock::Clock,
+    console::ConsoleInput,
+    device::Devices,
+    drive::{DRIVE_C, VirtualDrives, truncate_83},
+    environment::Environment,
+    files::{Attributes, FileTable},
+    find::Searches,
+    keyboard::{self, Keyboard},
+    memory::{self, FIRST_MCB, MEMORY_TOP, MemoryArena},
+    ports::{self, Ports},
+    process::Process,
+    program::{Format, LoadError, PSP, Program},
+    timer::Pit,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use unicorn_engine::{Arch, Mode, Prot, RegisterX86, Unicorn, uc_error};
 
 /// Addresses are 16 bit, but u64 makes it easier to work with unicorn
 pub struct Cpu {
-    ax: u64,
-    bx: u64,
-    cx: u64,
-    dx: u64,
-    si: u64,
-    di: u64,
-    sp: u64,
-    bp: u64,
-    ip: u64,
-    cs: u64,
-    ds: u64,
-    es: u64,
-    ss: u64,
-    fs: u64,
-    gs: u64,
+    pub ax: u64,
+    pub bx: u64,
+    pub cx: u64,
+    pub dx: u64,
+    pub si: u64,
+    pub di: u64,
+    pub sp: u64,
+    pub bp: u64,
+    pub ip: u64,
+    pub cs: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub ss: u64,
+    pub fs: u64,
+    pub gs: u64,
 }
 
 impl Cpu {
-    fn read_engine(engine: &Unicorn<EngineData>) -> Self {
+    pub fn read_engine(engine: &Unicorn<EngineData>) -> Self {
         let ax = engine.reg_read(RegisterX86::AX).unwrap();
         let bx = engine.reg_read(RegisterX86::BX).unwrap();
         let cx = engine.reg_read(RegisterX86::CX).unwrap();
@@ -134,6 +156,55 @@ impl Display for FarPointer {
     }
 }
 
+/// Overlay loaded by the program with AH=4Bh AL=03h
+pub struct Overlay {
+    pub path: String,
+    /// Segment the overlay was loaded at
+    pub segment: u16,
+    pub size: usize,
+    /// Overlay number from the MZ header
+    pub number: u16,
+}
+
+/// Why the emulation stopped, turned into the exit status of the emulator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Program terminated with AH=4Ch, AH=00h or INT 20h and the exit code in AL
+    Terminated(u8),
+    /// Program called an interrupt or a function the emulator doesn't implement
+    Unimplemented,
+    /// Unicorn failed to run the program, like on invalid memory access or instruction
+    Fault,
+}
+
+/// Starts the stderr line printed when the emulator gives up, together with the
+/// `--emulator-exit-code` it tells the failure apart from the program's own exit code
+pub const FAILURE_MARKER: &str = "emulator failure:";
+
+/// Default of `--emulator-exit-code`
+pub const DEFAULT_FAILURE_CODE: u8 = 120;
+
+/// Why the emulator gave up, the exit code is the `--emulator-exit-code` plus the
+/// number of the failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The program can't be loaded or an option file can't be read
+    Setup,
+    Unimplemented,
+    Fault,
+    /// The emulation stopped before the program exited, like when quitting the debugger
+    Stopped,
+}
+
+impl Failure {
+    /// Highest `--emulator-exit-code` that leaves room for the codes of every failure
+    pub const MAX_BASE: u8 = u8::MAX - Failure::Stopped as u8;
+
+    pub fn exit_code(self, base: u8) -> i32 {
+        base as i32 + self as i32
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct EngineBreak {
     addr: u64,
@@ -153,17 +224,85 @@ pub struct EngineData {
     breaks: HashMap<u64, EngineBreak>,
     /// started -> addr
     while_break: Option<(bool, u64)>,
-    exited: bool,
-    verbose: bool,
+    /// Set when the emulation can't continue anymore
+    pub exit: Option<ExitStatus>,
+    pub verbose: bool,
+    /// Translate console output from CP437 to UTF-8
+    pub cp437: bool,
+    /// Print the files the program changed when it exits
+    pub report: bool,
+    /// Exit code of the host process when the emulator gives up
+    pub failure_code: u8,
+    /// Keyboard input for the console functions
+    pub console: ConsoleInput,
+    /// Keys for the BIOS keyboard buffer
+    pub keyboard: Keyboard,
+    /// Where the printer and AUX output goes
+    pub devices: Devices,
+    /// Number of instructions run so far, the timer and scripted keys are timed with it
+    pub instructions: u64,
+    /// Devices on the I/O ports
+    pub ports: Ports,
+    /// Date and time for the DOS and BIOS clock functions
+    pub clock: Clock,
+    /// Files opened by the program
+    pub files: FileTable,
+    /// Hidden, system and archive bits the program has set
+    pub attributes: Attributes,
+    /// Host directories the program sees as drives
+    pub drives: VirtualDrives,
+    /// Directories searched with AH=4Eh
+    pub searches: Searches,
+    /// Conventional memory blocks
+    pub memory: MemoryArena,
+    /// PSP segment of the running program
+    pub psp_segment: u16,
+    pub overlays: Vec<Overlay>,
+    /// Programs that are running, the last one is the current program
+    pub processes: Vec<Process>,
+    /// Exit code of the last child program for AH=4Dh
+    pub return_code: Option<u16>,
 }
 
 impl EngineData {
-    fn new(program: Program) -> Self {
+    fn new(program: Program, args: &CliArgs) -> Self {
+        let mut drives = VirtualDrives::new();
+        drives.mount(DRIVE_C, args.c_drive(), args.read_only);
+        for (drive, dir) in &args.drives {
+            drives.mount(*drive, dir.clone(), args.read_only);
+        }
+        for (drive, dir) in &args.ro_drives {
+            drives.mount(*drive, dir.clone(), true);
+        }
+        let mut ports = Ports::new();
+        ports.register(Box::new(Pit::new(args.ips)));
+
         Self {
             program: Rc::new(program),
             breaks: HashMap::new(),
-            exited: false,
+            console: ConsoleInput::stdin(),
+            keyboard: Keyboard::new(),
+            devices: Devices::new(),
+            instructions: 0,
+            ports,
+            clock: match args.fixed_time {
+                Some(start) => Clock::fixed(start, args.ips),
+                None => Clock::host(args.ips),
+            },
+            files: FileTable::new(),
+            attributes: Attributes::new(),
+            drives,
+            searches: Searches::new(),
+            memory: MemoryArena::new(FIRST_MCB, MEMORY_TOP),
+            psp_segment: 0,
+            overlays: vec![],
+            processes: vec![],
+            return_code: None,
+            exit: None,
             verbose: false,
+            cp437: false,
+            report: false,
+            failure_code: DEFAULT_FAILURE_CODE,
             while_break: None,
         }
     }
@@ -179,6 +318,13 @@ impl EngineData {
     fn get_break_mut(&mut self, addr: u64) -> Option<&mut EngineBreak> {
         self.breaks.get_mut(&addr)
     }
+
+    /// Disk transfer area of the running program as segment and offset
+    pub fn dta(&self) -> (u16, u16) {
+        self.processes
+            .last()
+            .map_or((self.psp_segment, 0x80), |process| process.dta)
+    }
 }
 
 pub struct Engine<'a> {
@@ -197,45 +343,103 @@ impl<'a> Engine<'a> {
         &self.engine
     }
 
-    pub fn new(program: Program) -> Self {
-        let data = EngineData::new(program);
+    /// Load the program at its segment, fails if it doesn't fit in conventional memory
+    pub fn new(program: Program, args: &CliArgs) -> Result<Self, LoadError> {
+        let data = EngineData::new(program, args);
         let mut engine = Unicorn::new_with_data(Arch::X86, Mode::MODE_16, data).unwrap();
         engine.mem_map(0, 8 * 1024 * 1024, Prot::ALL).unwrap();
         let program = engine.get_data().program.clone();
 
+        let psp_segment = program.psp_segment() * 16;
+
+        let program_path = engine
+            .get_data()
+            .drives
+            .dos_path(Path::new(&args.program_path));
+        let program_path = program_path.unwrap_or_else(|| {
+            let name = Path::new(&args.program_path).file_name().unwrap();
+            format!("C:\\{}", truncate_83(&name.to_string_lossy()))
+        });
+        let program_name = Path::new(&args.program_path)
+            .file_stem()
+            .map_or(String::new(), |stem| stem.to_string_lossy().to_uppercase());
+
+        let psp_seg = (psp_segment >> 4) as u16;
+        // PSP, the load module and the extra memory requested in the header
+        let (min, max) = program.allocation()?;
+        let no_memory = |paragraphs: u32| LoadError::NoMemory {
+            segment: psp_seg,
+            paragraphs,
+        };
+        let memory = &mut engine.get_data_mut().memory;
+        let mut size = memory
+            .allocate_at(psp_seg, min, max, psp_seg, &program_name)
+            .map_err(|_| no_memory(min as u32))?;
+
+        // The environment goes in the free memory below the program like MsDos puts it,
+        // when the program is loaded too low for that it takes the end of the program's block
+        let env = Environment::new(&args.env, &program_path);
+        let env_size = env.paragraphs();
+        let env_segment = match memory.allocate(env_size, psp_seg) {
+            Ok(segment) => segment,
+            Err(_) => {
+                let needed = min as u32 + env_size as u32 + 1;
+                size = size
+                    .checked_sub(env_size + 1)
+                    .filter(|size| *size >= min)
+                    .ok_or(no_memory(needed))?;
+                memory.resize(psp_seg, size).unwrap();
+                memory
+                    .allocate(env_size, psp_seg)
+                    .map_err(|_| no_memory(needed))?
+            }
+        };
+
         // the start is a far pointer segment thingy so we need to multiply it with 16
         let start_segment = program.start() * 16;
-        let psp_segment = start_segment - 256;
         engine.mem_write(start_segment, program.data()).unwrap();
 
-        let psp = &PSP::new(0x2000, 0x0);
+        let data = engine.get_data_mut();
+        data.psp_segment = psp_seg;
+        data.processes.push(Process {
+            psp_segment: psp_seg,
+            path: program_path.clone(),
+            dta: (psp_seg, 0x80),
+        });
+        memory::write_chain(&mut engine);
+        bios::init(&mut engine);
+        engine
+            .mem_write(env_segment as u64 * 16, &env.to_bytes())
+            .unwrap();
+
+        let psp = &PSP::new(psp_seg + size, 0x0, env_segment, &args.program_args);
         let psp_data: &[u8] = psp.into();
         engine.mem_write(psp_segment, psp_data).unwrap();
 
+        let (cs, ip) = program.entry();
+        let (ss, sp) = program.stack();
+        engine.reg_write(RegisterX86::IP, ip).unwrap();
+        engine.reg_write(RegisterX86::SP, sp).unwrap();
+        // Programs start with interrupts enabled so the timer can run
         engine
-            .reg_write(RegisterX86::IP, program.header().initial_ip as u64)
-            .unwrap();
-        engine
-            .reg_write(RegisterX86::SP, program.header().initial_sp as u64)
-            .unwrap();
-        engine
-            .reg_write(
-                RegisterX86::CS,
-                program.header().initial_cs as u64 + program.start(),
-            )
-            .unwrap();
-        engine
-            .reg_write(
-                RegisterX86::SS,
-                program.header().initial_ss as u64 + program.start(),
-            )
+            .reg_write(RegisterX86::EFLAGS, 0x2 | INTERRUPT_FLAG)
             .unwrap();
+        engine.reg_write(RegisterX86::CS, cs).unwrap();
+        engine.reg_write(RegisterX86::SS, ss).unwrap();
+        if let Format::Com = program.format() {
+            // .COM programs can return to DOS with RET, which jumps to the INT 20h at PSP:0000
+            engine.mem_write(ss * 16 + sp, &[0, 0]).unwrap();
+        }
 
         engine.reg_write(RegisterX86::DS, psp_segment >> 4).unwrap();
         engine.reg_write(RegisterX86::ES, psp_segment >> 4).unwrap();
 
         engine
             .add_code_hook(program.start(), 0, |emu, addr, len| {
+                emu.get_data_mut().instructions += 1;
+                keyboard::tick(emu);
+                ports::tick(emu);
+
                 let fp = FarPointer::read_engine(&emu);
                 if emu.get_data().verbose {
                     let decoder = yaxpeax_x86::real_mode::InstDecoder::default();
@@ -269,113 +473,117 @@ impl<'a> Engine<'a> {
             })
             .unwrap();
 
-        engine
-            .add_intr_hook(|emu, num| {
-                let cpu = Cpu::read_engine(&emu);
-                if num == 0x21 {
-                    let ah = cpu.ax >> 8;
-                    if ah == 0x19 {
-                        // 2 C drive, let's assume that we always have C drive
-                       emu.reg_write(RegisterX86::AL, 2).unwrap();
-                    } else if ah == 0x25 {
-                        let al = cpu.ax & 0xff;
-                        let handler_ptr = (cpu.ds * 16 + cpu.dx) as u32;
-                        emu.mem_write(al * 4, &handler_ptr.to_le_bytes()).unwrap();
-                    } else if ah == 0x38 {
-                        println!("IGNORING COUNTRY DEPENDENT INFORMATION!!!!!")
-                    } else if ah == 0x30 {
-                        // TXLIST.EXE is checking for DOS version 2 so lets set the dos version to that for now
-                        emu.reg_write(RegisterX86::AL, 2).unwrap();
-                    } else if ah == 0x35 {
-                        let al = cpu.ax & 0xff;
-                        emu.reg_write(RegisterX86::BX, al * 4).unwrap();
-                        emu.reg_write(RegisterX86::ES, al * 4 + 2).unwrap();
-                    } else if ah == 0x40 {
-                        let ds = cpu.ds;
-                        let dx = cpu.dx;
-                        let addr = ds * 16 + dx;
-                        let data = emu.mem_read_as_vec(addr, cpu.cx as usize).unwrap();
-                        println!(
-                            "Write to fd '{}', string: '{}'",
-                            cpu.bx,
-                            String::from_utf8_lossy(&data)
-                        );
-                    } else if ah == 0x43 {
-                        let addr = cpu.ds * 16 +  cpu.dx;
-                        if cpu.ax & 0xff  == 1{
-                            println!("Cannot set file attributes, exiting...");
-                            emu.get_data_mut().exited = true;
-                            emu.emu_stop().unwrap();
-                            return;
-                        }
-
-                        let data = emu.mem_read_as_vec(addr, 128).unwrap();
-                        let null = data.iter().position(|byte| *byte == 0).expect("Couldn't find null termination from a string");
-                        let file_name = String::from_utf8_lossy(&data[..null]);
-                        if emu.get_data().verbose {
-                            println!("Checking for file {file_name}");
-                        }
+        engine.add_intr_hook(bios::interrupt).unwrap();
 
-                        // we don't have a filesystem so always set the error as: 02  File not found
-                        emu.reg_write(RegisterX86::AX, 0x02).unwrap();
-                        let eflags = emu.reg_read(RegisterX86::EFLAGS).unwrap();
-                        emu.reg_write(RegisterX86::EFLAGS, eflags | 1).unwrap();
-                    } else if ah == 0x44 {
-                        let al = cpu.ax & 0xff;
-                        if cpu.bx > 4 {
-                            println!("IOCTL functions are only implemented for default file handles. Exiting..");
-                            emu.get_data_mut().exited = true;
-                            emu.emu_stop().unwrap();
-                            return;
-                        }
+        engine.add_insn_in_hook(ports::port_in).unwrap();
+        engine.add_insn_out_hook(ports::port_out).unwrap();
 
-                        if al == 0 {
-                            // Mark device as character device
-                            emu.reg_write(RegisterX86::DX, 0x80).unwrap();
-                        } else {
-                            println!("Unimplemented IOCTL function {al:x}");
-                            emu.get_data_mut().exited = true;
-                            emu.emu_stop().unwrap();
-                        }
-                    } else if ah == 0x47 {
-                        let dst_addr = cpu.ds * 16 +  cpu.si;
-                        // always assume C: as the current path so the path is empty relative to it
-                        // ASCIIZ string make sure it's null terminated
-                        emu.mem_write(dst_addr, &[ 0]).unwrap();
-                    } else if ah == 0x4a {
-                        // We'll never run out of memory so we can assume that each sector has the maximum size of 0xffff
-                        // Dosbox is doing this so lets do it too for now?
-                        emu.reg_write(RegisterX86::BX, 0).unwrap();
-                        emu.reg_write(RegisterX86::ES, 0xffff).unwrap();
-                        emu.reg_write(RegisterX86::DI, 0xffff).unwrap();
-                    } else if ah == 0x4c {
-                        let al = cpu.ax & 0xff;
-                        println!("Program terminating with code '0x{al:x}', exiting...");
-                        emu.get_data_mut().exited = true;
-                        emu.emu_stop().unwrap();
-                    } else {
-                        println!("Unimplemented ah for 0x21: 0x{ah:x}, exiting...");
-                        emu.get_data_mut().exited = true;
-                        emu.emu_stop().unwrap();
-                        return;
-                    }
-                } else {
-                    println!("Unimplemented interrupt 0x{num:x}, exiting...");
-                    emu.get_data_mut().exited = true;
-                    emu.emu_stop().unwrap();
-                }
-            })
-            .unwrap();
+        Ok(Self { engine })
+    }
 
-        Self { engine }
+    /// The emulator with the program loaded, for testing the services without running it
+    #[cfg(test)]
+    pub fn into_emulator(self) -> Unicorn<'a, EngineData> {
+        self.engine
     }
 
     pub fn set_verbose(&mut self, verbose: bool) {
         self.engine.get_data_mut().verbose = verbose;
     }
 
+    pub fn set_cp437(&mut self, cp437: bool) {
+        self.engine.get_data_mut().cp437 = cp437;
+    }
+
+    /// Print every IN and OUT the program does
+    pub fn set_port_log(&mut self, log: bool) {
+        self.engine.get_data_mut().ports.log = log;
+    }
+
+    /// Stop when the program accesses the I/O port
+    pub fn add_port_break(&mut self, port: u16) {
+        self.engine.get_data_mut().ports.breaks.insert(port);
+    }
+
+    /// Keep the program's file changes in a copy-on-write directory
+    pub fn set_overlay(&mut self, dir: PathBuf) -> io::Result<()> {
+        self.engine.get_data_mut().drives.set_overlay(dir)
+    }
+
+    /// Write the printer output to a host file
+    pub fn capture_printer(&mut self, path: &Path) -> io::Result<()> {
+        self.engine.get_data_mut().devices.capture_printer(path)
+    }
+
+    /// Write the AUX output to a host file
+    pub fn capture_aux(&mut self, path: &Path) -> io::Result<()> {
+        self.engine.get_data_mut().devices.capture_aux(path)
+    }
+
+    /// Print the changed files when the program exits
+    pub fn set_report(&mut self, report: bool) {
+        self.engine.get_data_mut().report = report;
+    }
+
+    /// Type the BIOS keyboard input from a key script
+    pub fn set_keys(&mut self, keyboard: Keyboard) {
+        self.engine.get_data_mut().keyboard = keyboard;
+    }
+
+    /// Read the keyboard input from somewhere else than stdin
+    pub fn set_input(&mut self, input: ConsoleInput) {
+        self.engine.get_data_mut().console = input;
+    }
+
+    pub fn processes(&self) -> &[Process] {
+        &self.engine.get_data().processes
+    }
+
+    pub fn overlays(&self) -> &[Overlay] {
+        &self.engine.get_data().overlays
+    }
+
     pub fn exited(&self) -> bool {
-        self.engine.get_data().exited
+        self.engine.get_data().exit.is_some()
+    }
+
+    /// First exit code of the failures when the emulator gives up, see [`Failure`]
+    pub fn set_failure_code(&mut self, code: u8) {
+        self.engine.get_data_mut().failure_code = code;
+    }
+
+    /// Print the change report if it was asked for and return the exit status for the
+    /// host process. The program's own exit code is returned as is, an emulator failure
+    /// is reported on stderr with [`FAILURE_MARKER`] and the exit code of the failure.
+    pub fn finish(&self) -> i32 {
+        let data = self.engine.get_data();
+        if data.report {
+            eprint!("{}", data.drives.changes.report());
+        }
+
+        let (failure, reason) = match data.exit {
+            Some(ExitStatus::Terminated(code)) => return code as i32,
+            Some(ExitStatus::Unimplemented) => (
+                Failure::Unimplemented,
+                "the program used an unimplemented function",
+            ),
+            Some(ExitStatus::Fault) => (Failure::Fault, "unicorn failed to run the program"),
+            None => (
+                Failure::Stopped,
+                "the emulation stopped before the program exited",
+            ),
+        };
+        eprintln!("{FAILURE_MARKER} {reason}");
+        failure.exit_code(data.failure_code)
+    }
+
+    /// Stop the emulation for good if unicorn returned an error
+    fn check_fault(&mut self, result: Result<(), uc_error>) {
+        if let Err(err) = result {
+            let ip = FarPointer::read_engine(&self.engine);
+            eprintln!("Emulator fault {err:?} at [{ip}], exiting...");
+            self.engine.get_data_mut().exit = Some(ExitStatus::Fault);
+        }
     }
 
     pub fn add_break(&mut self, addr: u64) {
@@ -389,7 +597,9 @@ impl<'a> Engine<'a> {
 
     pub fn start(&mut self) {
         let ip = FarPointer::read_engine(&self.engine);
-        self.engine.emu_start(ip.address(), 8192, 0, 0).unwrap()
+        let result = self.engine.emu_start(ip.address(), 8192, 0, 0);
+        self.engine.get_data_mut().console.restore();
+        self.check_fault(result);
     }
 
     pub fn read_cpu(&self) -> Cpu {
@@ -410,6 +620,43 @@ impl<'a> Engine<'a> {
 
     pub fn step(&mut self) {
         let ip = FarPointer::read_engine(&self.engine);
-        self.engine.emu_start(ip.address(), 8192, 0, 1).unwrap()
+        let result = self.engine.emu_start(ip.address(), 8192, 0, 1);
+        self.engine.get_data_mut().console.restore();
+        self.check_fault(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::Engine;
+    use crate::{
+        cli::CliArgs,
+        program::{PSP, Program},
+    };
+
+    /// Environment segment and the end of the memory block from the PSP
+    fn psp_env(args: &[&str]) -> (u16, u16, u16) {
+        let args = CliArgs::parse_from(args);
+        let program = Program::from_bytes(vec![0xCD, 0x20], args.load_segment as u64).unwrap();
+        let psp = program.psp_segment();
+        let emu = Engine::new(program, &args).unwrap().into_emulator();
+        let block = emu.mem_read_as_vec(psp * 16, 0x30).unwrap();
+        let word = |offset: usize| u16::from_le_bytes([block[offset], block[offset + 1]]);
+        (psp as u16, word(PSP::ENV_SEGMENT as usize), word(2))
+    }
+
+    #[test]
+    fn environment_placement() {
+        // Below the program when there is room for it
+        let (psp, env, _) = psp_env(&["dos", "TEST.COM"]);
+        assert_eq!(env, 0x0701);
+        assert!(env < psp);
+
+        // After the program's block at the lowest load segment
+        let (psp, env, top) = psp_env(&["dos", "--load-segment", "711", "TEST.COM"]);
+        assert_eq!(psp, 0x0701);
+        assert_eq!(env, top + 1);
     }
 }
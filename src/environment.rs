@@ -0,0 +1,101 @@
+/// Variables every program gets unless they are overridden with `--env`
+const DEFAULT_VARS: [(&str, &str); 4] = [
+    ("COMSPEC", "C:\\COMMAND.COM"),
+    ("PATH", "C:\\"),
+    ("PROMPT", "$P$G"),
+    ("TEMP", "C:\\"),
+];
+
+/// MsDos environment block that the PSP points to
+pub struct Environment {
+    /// `KEY=VALUE` strings as bytes, a program can give its child any CP437 text
+    vars: Vec<Vec<u8>>,
+    /// Full path of the program, stored after the variables since DOS 3.0
+    program_path: String,
+}
+
+impl Environment {
+    pub fn new(vars: &[(String, String)], program_path: &str) -> Self {
+        let mut all: Vec<(String, String)> = DEFAULT_VARS
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        for (key, value) in vars {
+            let key = key.to_ascii_uppercase();
+            if let Some(var) = all.iter_mut().find(|(k, _)| *k == key) {
+                var.1 = value.clone();
+            } else {
+                all.push((key, value.clone()));
+            }
+        }
+
+        Self {
+            vars: all
+                .into_iter()
+                .map(|(key, value)| format!("{key}={value}").into_bytes())
+                .collect(),
+            program_path: program_path.into(),
+        }
+    }
+
+    /// Read the variables from an existing environment block, used when a program starts a child
+    pub fn from_block(block: &[u8], program_path: &str) -> Self {
+        let vars = block
+            .split(|byte| *byte == 0)
+            .take_while(|var| !var.is_empty())
+            .map(|var| var.to_vec())
+            .collect();
+
+        Self {
+            vars,
+            program_path: program_path.into(),
+        }
+    }
+
+    /// `KEY=VALUE\0` strings, an empty string, a word count of 1 and the program path
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for var in &self.vars {
+            bytes.extend_from_slice(var);
+            bytes.push(0);
+        }
+        bytes.push(0);
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(self.program_path.as_bytes());
+        bytes.push(0);
+        bytes
+    }
+
+    /// Size of the block in 16 byte paragraphs
+    pub fn paragraphs(&self) -> u16 {
+        self.to_bytes().len().div_ceil(16) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Environment;
+
+    #[test]
+    fn environment_block() {
+        let vars = vec![
+            ("path".to_string(), "C:\\BIN".to_string()),
+            ("LIB".to_string(), "C:\\LIB".to_string()),
+        ];
+        let env = Environment::new(&vars, "C:\\PROG.EXE");
+        let bytes = env.to_bytes();
+
+        let expected = b"COMSPEC=C:\\COMMAND.COM\0PATH=C:\\BIN\0PROMPT=$P$G\0TEMP=C:\\\0LIB=C:\\LIB\0\0\x01\0C:\\PROG.EXE\0";
+        assert_eq!(bytes, expected);
+        assert_eq!(env.paragraphs() as usize, expected.len().div_ceil(16));
+
+        let child = Environment::from_block(&bytes, "C:\\CHILD.EXE");
+        assert_eq!(child.vars, env.vars);
+
+        // CP437 text the parent put in the block is copied as it is
+        let block = b"NAME=Jos\x82\0\0\x01\0C:\\PROG.EXE\0";
+        let child = Environment::from_block(block, "C:\\PROG.EXE");
+        assert_eq!(child.to_bytes(), block);
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::device::CharDevice;
+
+/// MsDos extended error codes returned in AX when carry is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DosError {
+    InvalidFunction = 0x01,
+    FileNotFound = 0x02,
+    PathNotFound = 0x03,
+    TooManyOpenFiles = 0x04,
+    AccessDenied = 0x05,
+    InvalidHandle = 0x06,
+    MemoryControlBlocksDestroyed = 0x07,
+    InsufficientMemory = 0x08,
+    InvalidMemoryBlock = 0x09,
+    InvalidFormat = 0x0B,
+    InvalidAccessCode = 0x0C,
+    InvalidDrive = 0x0F,
+    RemoveCurrentDirectory = 0x10,
+    NotSameDevice = 0x11,
+    NoMoreFiles = 0x12,
+    FileExists = 0x50,
+}
+
+impl DosError {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    /// Error of a host file operation, a missing file is path not found when its
+    /// directory is missing too like MsDos reports it
+    pub fn for_path(err: io::Error, path: &Path) -> Self {
+        let no_dir = path.parent().is_some_and(|dir| !dir.is_dir());
+        if err.kind() == io::ErrorKind::NotFound && no_dir {
+            return DosError::PathNotFound;
+        }
+        err.into()
+    }
+}
+
+impl From<io::Error> for DosError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::NotFound => DosError::FileNotFound,
+            io::ErrorKind::AlreadyExists => DosError::FileExists,
+            _ => DosError::AccessDenied,
+        }
+    }
+}
+
+/// Access mode from the low bits of AL in AH=3Dh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl AccessMode {
+    pub fn from_al(al: u8) -> Result<Self, DosError> {
+        match al & 0b111 {
+            0 => Ok(AccessMode::Read),
+            1 => Ok(AccessMode::Write),
+            2 => Ok(AccessMode::ReadWrite),
+            _ => Err(DosError::InvalidAccessCode),
+        }
+    }
+
+    fn can_read(self) -> bool {
+        self != AccessMode::Write
+    }
+
+    pub fn can_write(self) -> bool {
+        self != AccessMode::Read
+    }
+}
+
+/// Host streams the default handles are connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdStream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+pub enum DosFile {
+    Console {
+        stream: StdStream,
+        /// Raw mode set with AH=44h AL=01h
+        binary: bool,
+    },
+    /// Character device opened by name or the standard AUX and printer handles
+    Device { device: CharDevice, binary: bool },
+    Host {
+        file: File,
+        mode: AccessMode,
+        /// The program has written to the file, its time is set when it is closed
+        modified: bool,
+        /// DOS path of the file for the change report
+        name: String,
+        /// The file is opened for reading from a read-only drive or the drive below the
+        /// overlay, so its time can't be changed either
+        protected: bool,
+    },
+}
+
+impl DosFile {
+    pub fn console(stream: StdStream) -> Self {
+        DosFile::Console {
+            stream,
+            binary: false,
+        }
+    }
+
+    pub fn device(device: CharDevice) -> Self {
+        DosFile::Device {
+            device,
+            binary: false,
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, DosError> {
+        match self {
+            // Keyboard input goes through the line editing of `ConsoleInput`, AH=3Fh reads
+            // it there, and CLOCK$ is read in `dos`
+            DosFile::Console { .. } | DosFile::Device { .. } => Ok(0),
+            DosFile::Host { file, mode, .. } => {
+                if !mode.can_read() {
+                    return Err(DosError::AccessDenied);
+                }
+                Ok(file.read(buf)?)
+            }
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, DosError> {
+        match self {
+            DosFile::Console {
+                stream: StdStream::Stdin,
+                ..
+            } => Ok(0),
+            // Flushed right away so stdout and stderr stay in the order the program wrote them
+            DosFile::Console {
+                stream: StdStream::Stdout,
+                ..
+            }
+            | DosFile::Device {
+                device: CharDevice::Con,
+                ..
+            } => {
+                let mut stdout = io::stdout();
+                stdout.write_all(buf)?;
+                stdout.flush()?;
+                Ok(buf.len())
+            }
+            DosFile::Console {
+                stream: StdStream::Stderr,
+                ..
+            } => {
+                io::stderr().write_all(buf)?;
+                Ok(buf.len())
+            }
+            // The printer, AUX and CLOCK$ are written in `dos::write_handle`, NUL throws
+            // the output away
+            DosFile::Device { .. } => Ok(buf.len()),
+            DosFile::Host {
+                file,
+                mode,
+                modified,
+                ..
+            } => {
+                if !mode.can_write() {
+                    return Err(DosError::AccessDenied);
+                }
+                *modified = true;
+                // Writing zero bytes truncates the file at the current position
+                if buf.is_empty() {
+                    let pos = file.stream_position()?;
+                    file.set_len(pos)?;
+                    return Ok(0);
+                }
+                file.write_all(buf)?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Modification time of the file, None for character devices
+    pub fn modified(&self) -> Result<Option<SystemTime>, DosError> {
+        match self {
+            DosFile::Console { .. } | DosFile::Device { .. } => Ok(None),
+            DosFile::Host { file, .. } => Ok(Some(file.metadata()?.modified()?)),
+        }
+    }
+
+    /// Set the modification time (AH=57h), closing the file doesn't change it anymore
+    pub fn set_modified(&mut self, time: SystemTime) -> Result<(), DosError> {
+        match self {
+            DosFile::Console { .. } | DosFile::Device { .. } => Ok(()),
+            DosFile::Host {
+                protected: true, ..
+            } => Err(DosError::AccessDenied),
+            DosFile::Host { file, modified, .. } => {
+                file.set_modified(time)?;
+                *modified = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// DOS path of the file, None for character devices
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            DosFile::Console { .. } | DosFile::Device { .. } => None,
+            DosFile::Host { name, .. } => Some(name),
+        }
+    }
+
+    /// The file position is at the end of the file, for the input status of AH=44h
+    pub fn at_end(&mut self) -> Result<bool, DosError> {
+        match self {
+            DosFile::Console { .. } | DosFile::Device { .. } => Ok(false),
+            DosFile::Host { file, .. } => Ok(file.stream_position()? >= file.metadata()?.len()),
+        }
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, DosError> {
+        match self {
+            // Seeking on a character device always succeeds and stays at 0
+            DosFile::Console { .. } | DosFile::Device { .. } => Ok(0),
+            DosFile::Host { file, .. } => Ok(file.seek(pos)?),
+        }
+    }
+}
+
+/// Same as the default size of the PSP job file table
+const MAX_HANDLES: usize = 20;
+
+/// Handles of a program as indexes to the open files
+type JobFileTable = [Option<usize>; MAX_HANDLES];
+
+/// File that is open with the number of handles of all programs that refer to it
+struct OpenFile {
+    file: DosFile,
+    references: usize,
+}
+
+/// Maps DOS file handles to the host files they are backed by.
+///
+/// Like the MsDos system file table the open files are shared by every program, each
+/// program has its own job file table for its handles. A child program gets a copy of
+/// its parent's table, a file is closed when no program has a handle for it anymore.
+pub struct FileTable {
+    files: Vec<Option<OpenFile>>,
+    /// Job file tables of the running programs, the last one is the current program's
+    jobs: Vec<JobFileTable>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        let files = [
+            DosFile::console(StdStream::Stdin),
+            DosFile::console(StdStream::Stdout),
+            DosFile::console(StdStream::Stderr),
+            DosFile::device(CharDevice::Aux),
+            DosFile::device(CharDevice::Printer),
+        ];
+        let mut job = [None; MAX_HANDLES];
+        for (handle, index) in job.iter_mut().zip(0..files.len()) {
+            *handle = Some(index);
+        }
+
+        Self {
+            files: files
+                .into_iter()
+                .map(|file| {
+                    Some(OpenFile {
+                        file,
+                        references: 1,
+                    })
+                })
+                .collect(),
+            jobs: vec![job],
+        }
+    }
+
+    fn job(&mut self) -> &mut JobFileTable {
+        self.jobs.last_mut().unwrap()
+    }
+
+    fn insert(&mut self, file: DosFile) -> Result<u16, DosError> {
+        let handle = self
+            .job()
+            .iter()
+            .position(|h| h.is_none())
+            .ok_or(DosError::TooManyOpenFiles)?;
+        let file = Some(OpenFile {
+            file,
+            references: 1,
+        });
+        let index = match self.files.iter().position(|f| f.is_none()) {
+            Some(index) => {
+                self.files[index] = file;
+                index
+            }
+            None => {
+                self.files.push(file);
+                self.files.len() - 1
+            }
+        };
+        self.job()[handle] = Some(index);
+        Ok(handle as u16)
+    }
+
+    /// Create a file or truncate it if it exists (AH=3Ch)
+    pub fn create(&mut self, path: &Path, name: String) -> Result<u16, DosError> {
+        self.create_file(path, name, false)
+    }
+
+    /// Create a file that doesn't exist yet (AH=5Ah and 5Bh)
+    pub fn create_new(&mut self, path: &Path, name: String) -> Result<u16, DosError> {
+        self.create_file(path, name, true)
+    }
+
+    fn create_file(&mut self, path: &Path, name: String, new: bool) -> Result<u16, DosError> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        if new {
+            options.create_new(true);
+        } else {
+            options.create(true).truncate(true);
+        }
+        let file = options.open(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => DosError::PathNotFound,
+            _ => err.into(),
+        })?;
+
+        self.insert(DosFile::Host {
+            file,
+            mode: AccessMode::ReadWrite,
+            modified: true,
+            name,
+            protected: false,
+        })
+    }
+
+    pub fn open(
+        &mut self,
+        path: &Path,
+        mode: AccessMode,
+        name: String,
+        protected: bool,
+    ) -> Result<u16, DosError> {
+        if path.is_dir() {
+            return Err(DosError::AccessDenied);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode.can_write())
+            .open(path)
+            .map_err(|err| DosError::for_path(err, path))?;
+
+        self.insert(DosFile::Host {
+            file,
+            mode,
+            modified: false,
+            name,
+            protected,
+        })
+    }
+
+    /// Open a character device by its name (AH=3Ch, 3Dh and 5Bh)
+    pub fn open_device(&mut self, device: CharDevice) -> Result<u16, DosError> {
+        self.insert(DosFile::device(device))
+    }
+
+    /// Close the handle. The file is closed when it was the last handle for it, a file
+    /// the program wrote to gets `now` as its modification time like MsDos stamps the
+    /// directory entry.
+    pub fn close(&mut self, handle: u16, now: SystemTime) -> Result<(), DosError> {
+        let index = self
+            .job()
+            .get_mut(handle as usize)
+            .and_then(|h| h.take())
+            .ok_or(DosError::InvalidHandle)?;
+        self.release(index, now);
+        Ok(())
+    }
+
+    fn release(&mut self, index: usize, now: SystemTime) {
+        let open = self.files[index].as_mut().unwrap();
+        open.references -= 1;
+        if open.references > 0 {
+            return;
+        }
+        if let Some(OpenFile {
+            file:
+                DosFile::Host {
+                    file,
+                    modified: true,
+                    ..
+                },
+            ..
+        }) = self.files[index].take()
+        {
+            // The time is only informational, so failing to set it isn't an error
+            let _ = file.set_modified(now);
+        }
+    }
+
+    /// A child program starts with the handles of the running program
+    pub fn inherit(&mut self) {
+        let job = *self.job();
+        for index in job.iter().flatten() {
+            self.files[*index].as_mut().unwrap().references += 1;
+        }
+        self.jobs.push(job);
+    }
+
+    /// The child program exits, its handles are closed
+    pub fn exit(&mut self, now: SystemTime) {
+        let job = self.jobs.pop().unwrap();
+        for index in job.into_iter().flatten() {
+            self.release(index, now);
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: u16) -> Result<&mut DosFile, DosError> {
+        let index = self
+            .job()
+            .get(handle as usize)
+            .copied()
+            .flatten()
+            .ok_or(DosError::InvalidHandle)?;
+        Ok(&mut self.files[index].as_mut().unwrap().file)
+    }
+}
+
+/// Attribute bits of directory entries
+pub const READ_ONLY: u16 = 0x01;
+pub const HIDDEN: u16 = 0x02;
+pub const SYSTEM: u16 = 0x04;
+pub const VOLUME_LABEL: u16 = 0x08;
+pub const DIRECTORY: u16 = 0x10;
+pub const ARCHIVE: u16 = 0x20;
+
+/// The owner can't write to the file, the other permission bits don't matter to DOS
+#[cfg(unix)]
+pub fn read_only(permissions: &Permissions) -> bool {
+    permissions.mode() & 0o200 == 0
+}
+
+#[cfg(not(unix))]
+pub fn read_only(permissions: &Permissions) -> bool {
+    permissions.readonly()
+}
+
+/// Only the owner's write permission changes, `set_readonly(false)` would make the file
+/// writable for everyone on Unix
+#[cfg(unix)]
+fn set_read_only(permissions: &mut Permissions, read_only: bool) {
+    let mode = permissions.mode();
+    permissions.set_mode(if read_only {
+        mode & !0o200
+    } else {
+        mode | 0o200
+    });
+}
+
+#[cfg(not(unix))]
+fn set_read_only(permissions: &mut Permissions, read_only: bool) {
+    permissions.set_readonly(read_only);
+}
+
+/// Attributes the host file system can't store. Read-only is the host permission and the
+/// directory bit comes from the host, hidden, system and archive are kept here once the
+/// program has set them.
+pub struct Attributes {
+    stored: HashMap<PathBuf, u16>,
+}
+
+impl Attributes {
+    pub fn new() -> Self {
+        Self {
+            stored: HashMap::new(),
+        }
+    }
+
+    /// Attributes of a host file (AH=43h AL=00h). Files are archived until the program
+    /// clears the bit and host files starting with a dot are hidden.
+    pub fn get(&self, path: &Path) -> Result<u16, DosError> {
+        let meta = fs::metadata(path).map_err(|err| DosError::for_path(err, path))?;
+        let mut attributes = match self.stored.get(path) {
+            Some(stored) => *stored,
+            None => {
+                let hidden = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with('.'));
+                let hidden = if hidden { HIDDEN } else { 0 };
+                let archive = if meta.is_dir() { 0 } else { ARCHIVE };
+                hidden | archive
+            }
+        };
+        if meta.is_dir() {
+            attributes |= DIRECTORY;
+        }
+        if read_only(&meta.permissions()) {
+            attributes |= READ_ONLY;
+        }
+        Ok(attributes)
+    }
+
+    /// Set the attributes of a host file (AH=43h AL=01h), the volume label and directory
+    /// bits can't be changed this way
+    pub fn set(&mut self, path: &Path, attributes: u16) -> Result<(), DosError> {
+        let meta = fs::metadata(path).map_err(|err| DosError::for_path(err, path))?;
+        let directory = if meta.is_dir() { DIRECTORY } else { 0 };
+        if attributes & VOLUME_LABEL != 0 || attributes & DIRECTORY & !directory != 0 {
+            return Err(DosError::AccessDenied);
+        }
+
+        let mut permissions = meta.permissions();
+        let protect = attributes & READ_ONLY != 0;
+        if read_only(&permissions) != protect {
+            set_read_only(&mut permissions, protect);
+            fs::set_permissions(path, permissions)?;
+        }
+        self.stored
+            .insert(path.into(), attributes & (HIDDEN | SYSTEM | ARCHIVE));
+        Ok(())
+    }
+
+    /// The file was deleted or renamed, its attributes go with it
+    pub fn moved(&mut self, from: &Path, to: Option<&Path>) {
+        if let Some(attributes) = self.stored.remove(from)
+            && let Some(to) = to
+        {
+            self.stored.insert(to.into(), attributes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::SystemTime};
+
+    use super::{AccessMode, Attributes, DosError, FileTable};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn child_handles() {
+        let dir = TempDir::new("files-test");
+        fs::write(dir.0.join("PARENT.TXT"), b"parent").unwrap();
+        fs::write(dir.0.join("CHILD.TXT"), b"child").unwrap();
+        let now = SystemTime::now();
+        let mut files = FileTable::new();
+        let parent = files
+            .open(
+                &dir.0.join("PARENT.TXT"),
+                AccessMode::Read,
+                "C:\\PARENT.TXT".into(),
+                false,
+            )
+            .unwrap();
+
+        // The child closes the inherited handle and gets the same number for its own file
+        files.inherit();
+        files.close(parent, now).unwrap();
+        assert!(matches!(
+            files.get_mut(parent),
+            Err(DosError::InvalidHandle)
+        ));
+        let child = files
+            .open(
+                &dir.0.join("CHILD.TXT"),
+                AccessMode::Read,
+                "C:\\CHILD.TXT".into(),
+                false,
+            )
+            .unwrap();
+        assert_eq!(child, parent);
+        files.exit(now);
+
+        let mut buf = [0u8; 6];
+        assert_eq!(files.get_mut(parent).unwrap().read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf, b"parent");
+        // The child's file was closed when it exited
+        let names: Vec<_> = files
+            .files
+            .iter()
+            .flatten()
+            .filter_map(|open| open.file.name())
+            .collect();
+        assert_eq!(names, ["C:\\PARENT.TXT"]);
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = TempDir::new("missing-test");
+        let mut files = FileTable::new();
+        let mut open = |path| files.open(&dir.0.join(path), AccessMode::Read, "".into(), false);
+        assert!(matches!(open("NONE.TXT"), Err(DosError::FileNotFound)));
+        assert!(matches!(
+            open("NODIR/NONE.TXT"),
+            Err(DosError::PathNotFound)
+        ));
+
+        let attributes = Attributes::new();
+        assert!(matches!(
+            attributes.get(&dir.0.join("NONE.TXT")),
+            Err(DosError::FileNotFound)
+        ));
+        assert!(matches!(
+            attributes.get(&dir.0.join("NODIR/NONE.TXT")),
+            Err(DosError::PathNotFound)
+        ));
+    }
+}
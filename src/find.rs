@@ -0,0 +1,291 @@
+use std::fs;
+
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    clock::{dos_date, dos_time, from_system_time},
+    dos::read_path,
+    drive::{DosPath, VirtualDrives},
+    engine::{Cpu, EngineData},
+    files::{DIRECTORY, DosError, HIDDEN, SYSTEM, VOLUME_LABEL},
+    program::fcb_name,
+};
+
+/// Offsets in the 43 byte search record in the DTA. The first 21 bytes are reserved for
+/// DOS, they hold the search so AH=4Fh can continue it.
+const DTA_DRIVE: usize = 0x00;
+const DTA_PATTERN: usize = 0x01;
+const DTA_SEARCH_ATTRIBUTES: usize = 0x0C;
+const DTA_INDEX: usize = 0x0D;
+const DTA_DIRECTORY: usize = 0x0F;
+const DTA_ATTRIBUTES: usize = 0x15;
+const DTA_TIME: usize = 0x16;
+const DTA_DATE: usize = 0x18;
+const DTA_SIZE: usize = 0x1A;
+const DTA_NAME: usize = 0x1E;
+const DTA_RECORD_SIZE: usize = 0x2B;
+
+/// Directories that have been searched, the DTA refers to them by their index like MsDos
+/// refers to the directory by its cluster
+pub struct Searches {
+    directories: Vec<DosPath>,
+}
+
+impl Searches {
+    pub fn new() -> Self {
+        Self {
+            directories: vec![],
+        }
+    }
+
+    fn id(&mut self, dir: &DosPath) -> u16 {
+        let idx = match self.directories.iter().position(|known| known == dir) {
+            Some(idx) => idx,
+            None => {
+                self.directories.push(dir.clone());
+                self.directories.len() - 1
+            }
+        };
+        idx as u16
+    }
+
+    fn directory(&self, id: u16) -> Option<&DosPath> {
+        self.directories.get(id as usize)
+    }
+}
+
+/// Entry of a directory listing
+struct Entry {
+    name: String,
+    attributes: u16,
+    time: u16,
+    date: u16,
+    size: u32,
+}
+
+/// `?` in the pattern matches any character, including the padding
+fn matches(pattern: &[u8; 11], name: &[u8; 11]) -> bool {
+    pattern
+        .iter()
+        .zip(name)
+        .all(|(pattern, name)| *pattern == b'?' || pattern == name)
+}
+
+/// Name in the FCB format for matching, `.` and `..` can't go through the file name rules
+fn padded_name(name: &str) -> [u8; 11] {
+    let mut padded = [b' '; 11];
+    if name == "." || name == ".." {
+        padded[..name.len()].copy_from_slice(name.as_bytes());
+        padded
+    } else {
+        fcb_name(name)
+    }
+}
+
+/// Hidden, system and directory entries are only found when the search asks for them,
+/// normal files are always found
+fn attributes_match(search: u16, attributes: u16) -> bool {
+    attributes & (HIDDEN | SYSTEM | DIRECTORY) & !search == 0
+}
+
+/// Volume label of the drive, the name of the host directory it is mounted from
+pub fn volume_label(drives: &VirtualDrives, drive: u8) -> Option<String> {
+    let root = drives.drive(drive).ok()?.root();
+    let name = root
+        .canonicalize()
+        .ok()?
+        .file_name()?
+        .to_str()?
+        .to_ascii_uppercase();
+    let label: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(11)
+        .collect();
+    (!label.is_empty()).then_some(label)
+}
+
+/// List a directory in the order the search goes through it, `.` and `..` come first in
+/// subdirectories like they do on a FAT disk
+fn list(data: &EngineData, dir: &DosPath) -> Result<Vec<Entry>, DosError> {
+    let host = data.drives.host_path(dir)?;
+    if !host.is_dir() {
+        return Err(DosError::PathNotFound);
+    }
+
+    let mut names = vec![];
+    if !dir.components.is_empty() {
+        names.push((".".to_string(), host.clone()));
+        names.push(("..".to_string(), host.clone()));
+    }
+    names.extend(data.drives.entries(dir)?);
+
+    let mut entries = vec![];
+    for (name, path) in names {
+        // Entries that disappeared while listing are skipped
+        let (Ok(meta), Ok(attributes)) = (fs::metadata(&path), data.attributes.get(&path)) else {
+            continue;
+        };
+        let modified = meta.modified().map(from_system_time).ok();
+        entries.push(Entry {
+            name,
+            attributes,
+            time: modified.map_or(0, |time| dos_time(time.time())),
+            date: modified.map_or(0, |time| dos_date(time.date())),
+            size: if meta.is_dir() {
+                0
+            } else {
+                meta.len().min(u32::MAX as u64) as u32
+            },
+        });
+    }
+    Ok(entries)
+}
+
+/// Find the first entry from `start` that matches the search saved in the record
+fn search(data: &EngineData, dir: &DosPath, record: &[u8], start: u16) -> Option<(u16, Entry)> {
+    let pattern: [u8; 11] = record[DTA_PATTERN..DTA_PATTERN + 11].try_into().unwrap();
+    let search_attributes = record[DTA_SEARCH_ATTRIBUTES] as u16;
+
+    // Only the label is returned when the search is for the label alone
+    if search_attributes == VOLUME_LABEL {
+        if start > 0 || !dir.components.is_empty() {
+            return None;
+        }
+        let label = volume_label(&data.drives, dir.drive)?;
+        let entry = Entry {
+            name: label,
+            attributes: VOLUME_LABEL,
+            time: 0,
+            date: 0,
+            size: 0,
+        };
+        return Some((1, entry));
+    }
+
+    list(data, dir)
+        .ok()?
+        .into_iter()
+        .enumerate()
+        .skip(start as usize)
+        .find(|(_, entry)| {
+            matches(&pattern, &padded_name(&entry.name))
+                && attributes_match(search_attributes, entry.attributes)
+        })
+        .map(|(idx, entry)| (idx as u16 + 1, entry))
+}
+
+/// Fill the found entry to the record, the index is where AH=4Fh continues from
+fn fill_record(record: &mut [u8], next: u16, entry: &Entry) {
+    record[DTA_INDEX..DTA_INDEX + 2].copy_from_slice(&next.to_le_bytes());
+    record[DTA_ATTRIBUTES] = entry.attributes as u8;
+    record[DTA_TIME..DTA_TIME + 2].copy_from_slice(&entry.time.to_le_bytes());
+    record[DTA_DATE..DTA_DATE + 2].copy_from_slice(&entry.date.to_le_bytes());
+    record[DTA_SIZE..DTA_SIZE + 4].copy_from_slice(&entry.size.to_le_bytes());
+    record[DTA_NAME..].fill(0);
+    let name = entry.name.as_bytes();
+    let len = name.len().min(12);
+    record[DTA_NAME..DTA_NAME + len].copy_from_slice(&name[..len]);
+}
+
+/// Address of the disk transfer area of the running program
+fn dta_address(emu: &Unicorn<EngineData>) -> u64 {
+    let (segment, offset) = emu.get_data().dta();
+    segment as u64 * 16 + offset as u64
+}
+
+/// Start a search for the path with wildcards in DS:DX (AH=4Eh).
+///
+/// CX has the attributes of the entries to find besides normal files. The search is saved
+/// in the DTA record so the program can continue it with AH=4Fh.
+pub fn find_first(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), DosError> {
+    let path = read_path(emu, cpu.ds * 16 + cpu.dx)?;
+    let data = emu.get_data_mut();
+    let mut dir = data.drives.parse(&path)?;
+    let name = dir.components.pop().ok_or(DosError::NoMoreFiles)?;
+    let host = data.drives.host_path(&dir)?;
+    if !host.is_dir() {
+        return Err(DosError::PathNotFound);
+    }
+
+    let mut record = [0u8; DTA_RECORD_SIZE];
+    record[DTA_DRIVE] = dir.drive + 1;
+    record[DTA_PATTERN..DTA_PATTERN + 11].copy_from_slice(&padded_name(&name));
+    // Read-only and archive files are found anyway
+    record[DTA_SEARCH_ATTRIBUTES] =
+        (cpu.cx as u16 & (HIDDEN | SYSTEM | VOLUME_LABEL | DIRECTORY)) as u8;
+    let id = data.searches.id(&dir);
+    record[DTA_DIRECTORY..DTA_DIRECTORY + 2].copy_from_slice(&id.to_le_bytes());
+
+    let (next, entry) = search(data, &dir, &record, 0).ok_or(DosError::NoMoreFiles)?;
+    fill_record(&mut record, next, &entry);
+    let addr = dta_address(emu);
+    emu.mem_write(addr, &record).unwrap();
+    Ok(())
+}
+
+/// Continue the search in the DTA record (AH=4Fh)
+pub fn find_next(emu: &mut Unicorn<EngineData>) -> Result<(), DosError> {
+    let addr = dta_address(emu);
+    let mut record = [0u8; DTA_RECORD_SIZE];
+    emu.mem_read(addr, &mut record).unwrap();
+
+    let id = u16::from_le_bytes([record[DTA_DIRECTORY], record[DTA_DIRECTORY + 1]]);
+    let start = u16::from_le_bytes([record[DTA_INDEX], record[DTA_INDEX + 1]]);
+    let data = emu.get_data();
+    let dir = data.searches.directory(id).ok_or(DosError::NoMoreFiles)?;
+    let (next, entry) = search(data, dir, &record, start).ok_or(DosError::NoMoreFiles)?;
+    fill_record(&mut record, next, &entry);
+    emu.mem_write(addr, &record).unwrap();
+    Ok(())
+}
+
+/// Set the disk transfer area to DS:DX (AH=1Ah)
+pub fn set_dta(emu: &mut Unicorn<EngineData>, cpu: &Cpu) {
+    let dta = (cpu.ds as u16, cpu.dx as u16);
+    if let Some(process) = emu.get_data_mut().processes.last_mut() {
+        process.dta = dta;
+    }
+}
+
+/// Return the disk transfer area in ES:BX (AH=2Fh)
+pub fn get_dta(emu: &mut Unicorn<EngineData>) {
+    let (segment, offset) = emu.get_data().dta();
+    emu.reg_write(RegisterX86::ES, segment as u64).unwrap();
+    emu.reg_write(RegisterX86::BX, offset as u64).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attributes_match, matches, padded_name};
+    use crate::files::{DIRECTORY, HIDDEN};
+
+    #[test]
+    fn wildcards() {
+        let pattern = padded_name("*.TXT");
+        assert!(matches(&pattern, &padded_name("README.TXT")));
+        assert!(matches(&pattern, &padded_name("A.TXT")));
+        assert!(!matches(&pattern, &padded_name("README.DOC")));
+        assert!(!matches(&pattern, &padded_name("README")));
+
+        let pattern = padded_name("*.*");
+        assert!(matches(&pattern, &padded_name("README")));
+        assert!(matches(&pattern, &padded_name("..")));
+
+        let pattern = padded_name("FILE?.C");
+        assert!(matches(&pattern, &padded_name("FILE1.C")));
+        assert!(matches(&pattern, &padded_name("FILE.C")));
+        assert!(!matches(&pattern, &padded_name("FILE12.C")));
+    }
+
+    #[test]
+    fn attribute_filter() {
+        assert!(attributes_match(0, 0x20));
+        // Read-only files are normal files for the search
+        assert!(attributes_match(0, 0x21));
+        assert!(!attributes_match(0, DIRECTORY));
+        assert!(attributes_match(DIRECTORY, DIRECTORY));
+        assert!(!attributes_match(DIRECTORY, 0x20 | HIDDEN));
+        assert!(attributes_match(HIDDEN | DIRECTORY, DIRECTORY | HIDDEN));
+    }
+}
@@ -0,0 +1,298 @@
+use std::io::{self, IsTerminal};
+
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    device::CharDevice,
+    drive::drive_number,
+    engine::{Cpu, EngineData},
+    files::{DosError, DosFile, StdStream},
+    find::volume_label,
+};
+
+/// Bits of the device information word of AL=00h. Files have the drive number in bits
+/// 0-5 and bit 6 set until they are written to, character devices have bit 7 set.
+const INFO_STDIN: u16 = 0x01;
+const INFO_STDOUT: u16 = 0x02;
+const INFO_NUL: u16 = 0x04;
+const INFO_CLOCK: u16 = 0x08;
+/// The device supports the fast console output of INT 29h
+const INFO_SPECIAL: u16 = 0x10;
+const INFO_BINARY: u16 = 0x20;
+/// Not written for files, not at the end of the input for devices
+const INFO_CLEAN: u16 = 0x40;
+const INFO_DEVICE: u16 = 0x80;
+
+/// Device information of CON like MsDos reports it for the standard handles
+const CON_INFO: u16 = INFO_DEVICE | INFO_CLEAN | INFO_SPECIAL | INFO_STDOUT | INFO_STDIN;
+
+/// Categories and functions of the generic IOCTL for block devices (AL=0Dh)
+const CATEGORY_DISK: u8 = 0x08;
+const SET_DEVICE_PARAMETERS: u8 = 0x40;
+const SET_MEDIA_ID: u8 = 0x46;
+const GET_DEVICE_PARAMETERS: u8 = 0x60;
+const GET_MEDIA_ID: u8 = 0x66;
+
+/// Geometry reported for every drive, a 512 MiB FAT16 fixed disk
+const SECTORS: u32 = 0x100000;
+const SECTORS_PER_CLUSTER: u8 = 32;
+const SECTORS_PER_FAT: u16 = 128;
+const SECTORS_PER_TRACK: u16 = 63;
+const HEADS: u16 = 16;
+const FIXED_DISK: u8 = 0x05;
+const MEDIA_FIXED: u8 = 0xF8;
+
+/// Device information of a handle (AL=00h), redirected standard handles are files on
+/// the current drive like they are with `PROG < IN > OUT`
+fn device_info(data: &mut EngineData, handle: u16) -> Result<u16, DosError> {
+    let current = data.drives.current() as u16;
+    let redirected = data.console.redirected();
+    let info = match data.files.get_mut(handle)? {
+        DosFile::Console { stream, binary } => {
+            let terminal = match stream {
+                StdStream::Stdin => !redirected,
+                StdStream::Stdout => io::stdout().is_terminal(),
+                StdStream::Stderr => io::stderr().is_terminal(),
+            };
+            match (terminal, *binary) {
+                (false, _) => current | INFO_CLEAN,
+                (true, false) => CON_INFO,
+                (true, true) => CON_INFO | INFO_BINARY,
+            }
+        }
+        DosFile::Device { device, binary } => {
+            let info = match device {
+                CharDevice::Con => CON_INFO,
+                // NUL is always at the end of its input
+                CharDevice::Nul => INFO_DEVICE | INFO_NUL,
+                CharDevice::Clock => INFO_DEVICE | INFO_CLEAN | INFO_CLOCK,
+                CharDevice::Printer | CharDevice::Aux => INFO_DEVICE | INFO_CLEAN,
+            };
+            if *binary { info | INFO_BINARY } else { info }
+        }
+        DosFile::Host { name, modified, .. } => {
+            let drive = name.chars().next().and_then(drive_number);
+            let clean = if *modified { 0 } else { INFO_CLEAN };
+            drive.unwrap_or(data.drives.current()) as u16 | clean
+        }
+    };
+    Ok(info)
+}
+
+/// Set the device information (AL=01h), only the raw mode of devices can be changed
+fn set_device_info(data: &mut EngineData, handle: u16, info: u16) -> Result<(), DosError> {
+    match data.files.get_mut(handle)? {
+        DosFile::Console { binary, .. } | DosFile::Device { binary, .. } if info & 0xff00 == 0 => {
+            *binary = info & INFO_BINARY != 0;
+            Ok(())
+        }
+        _ => Err(DosError::InvalidFunction),
+    }
+}
+
+/// Input status (AL=06h), 0FFh when a read would return data
+fn input_status(data: &mut EngineData, handle: u16) -> Result<u8, DosError> {
+    let ready = match data.files.get_mut(handle)? {
+        DosFile::Console {
+            stream: StdStream::Stdin,
+            ..
+        }
+        | DosFile::Device {
+            device: CharDevice::Con,
+            ..
+        } => data.console.available(),
+        DosFile::Console { .. }
+        | DosFile::Device {
+            device: CharDevice::Clock,
+            ..
+        } => true,
+        // NUL, the printer and AUX have no input
+        DosFile::Device { .. } => false,
+        file => !file.at_end()?,
+    };
+    Ok(if ready { 0xff } else { 0 })
+}
+
+/// Drive number from BL, 0 is the default drive and 1 = A:
+fn drive(data: &EngineData, bl: u64) -> Result<u8, DosError> {
+    let drive = match bl & 0xff {
+        0 => data.drives.current(),
+        drive => drive as u8 - 1,
+    };
+    data.drives.drive(drive)?;
+    Ok(drive)
+}
+
+/// Parameter block of the get device parameters function, the type and BPB of the drive
+fn device_parameters() -> [u8; 0x20] {
+    let mut block = [0u8; 0x20];
+    block[1] = FIXED_DISK;
+    // Not removable
+    block[2..4].copy_from_slice(&1u16.to_le_bytes());
+    let cylinders = SECTORS / (SECTORS_PER_TRACK as u32 * HEADS as u32);
+    block[4..6].copy_from_slice(&(cylinders as u16).to_le_bytes());
+
+    let bpb = &mut block[7..];
+    bpb[0..2].copy_from_slice(&512u16.to_le_bytes());
+    bpb[2] = SECTORS_PER_CLUSTER;
+    bpb[3..5].copy_from_slice(&1u16.to_le_bytes());
+    bpb[5] = 2;
+    bpb[6..8].copy_from_slice(&512u16.to_le_bytes());
+    // The 16 bit sector count is 0 when the 32 bit one is used
+    bpb[0x0A] = MEDIA_FIXED;
+    bpb[0x0B..0x0D].copy_from_slice(&SECTORS_PER_FAT.to_le_bytes());
+    bpb[0x0D..0x0F].copy_from_slice(&SECTORS_PER_TRACK.to_le_bytes());
+    bpb[0x0F..0x11].copy_from_slice(&HEADS.to_le_bytes());
+    bpb[0x11..0x15].copy_from_slice(&(SECTORS_PER_TRACK as u32).to_le_bytes());
+    bpb[0x15..0x19].copy_from_slice(&SECTORS.to_le_bytes());
+    block
+}
+
+/// Media ID of the get media ID function, the serial number is made up from the drive
+fn media_id(data: &EngineData, drive: u8) -> [u8; 0x19] {
+    let mut id = [0u8; 0x19];
+    let serial = 0x1980_0000 | drive as u32;
+    id[2..6].copy_from_slice(&serial.to_le_bytes());
+    let label = volume_label(&data.drives, drive).unwrap_or("NO NAME".into());
+    id[6..0x11].fill(b' ');
+    id[6..6 + label.len()].copy_from_slice(label.as_bytes());
+    id[0x11..].copy_from_slice(b"FAT16   ");
+    id
+}
+
+/// Generic IOCTL for block devices (AL=0Dh), CH is the category and CL the function.
+///
+/// The drives can't be formatted or read by track, so only the parameters and the media
+/// ID are supported. Setting them is accepted and ignored.
+fn generic_block(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), DosError> {
+    let drive = drive(emu.get_data(), cpu.bx)?;
+    let category = (cpu.cx >> 8) as u8;
+    let function = cpu.cx as u8;
+    if category != CATEGORY_DISK {
+        return Err(DosError::InvalidFunction);
+    }
+
+    let addr = cpu.ds * 16 + cpu.dx;
+    match function {
+        SET_DEVICE_PARAMETERS | SET_MEDIA_ID => {}
+        GET_DEVICE_PARAMETERS => {
+            // The special functions byte is given by the caller
+            emu.mem_write(addr + 1, &device_parameters()[1..]).unwrap();
+        }
+        GET_MEDIA_ID => {
+            // The info level is given by the caller
+            let id = media_id(emu.get_data(), drive);
+            emu.mem_write(addr + 2, &id[2..]).unwrap();
+        }
+        _ => return Err(DosError::InvalidFunction),
+    }
+    Ok(())
+}
+
+/// IOCTL functions (AH=44h) for handles and drives, AL is the function.
+///
+/// C runtimes check every handle at startup with AL=00h to know which ones are devices.
+pub fn ioctl(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), DosError> {
+    let al = cpu.ax & 0xff;
+    let handle = cpu.bx as u16;
+    let data = emu.get_data_mut();
+    match al {
+        0x00 => {
+            let info = device_info(data, handle)?;
+            emu.reg_write(RegisterX86::DX, info as u64).unwrap();
+        }
+        0x0a => {
+            // Bit 15 is set for handles of network files, the drives are all local
+            data.files.get_mut(handle)?;
+            emu.reg_write(RegisterX86::DX, 0).unwrap();
+        }
+        0x01 => set_device_info(data, handle, cpu.dx as u16)?,
+        0x06 => {
+            let status = input_status(data, handle)?;
+            emu.reg_write(RegisterX86::AL, status as u64).unwrap();
+        }
+        0x07 => {
+            // Output never has to wait
+            data.files.get_mut(handle)?;
+            emu.reg_write(RegisterX86::AL, 0xff).unwrap();
+        }
+        0x08 => {
+            drive(data, cpu.bx)?;
+            // AX=1 is a fixed disk
+            emu.reg_write(RegisterX86::AX, 1).unwrap();
+        }
+        0x09 => {
+            drive(data, cpu.bx)?;
+            // Bit 12 would be a network drive
+            emu.reg_write(RegisterX86::DX, 0).unwrap();
+        }
+        // The sharing retry count doesn't matter without file sharing
+        0x0b => {}
+        0x0d => generic_block(emu, cpu)?,
+        0x0e => {
+            drive(data, cpu.bx)?;
+            // Every drive has only one letter
+            emu.reg_write(RegisterX86::AL, 0).unwrap();
+        }
+        0x0f => {
+            drive(data, cpu.bx)?;
+        }
+        0x11 => {
+            // Query if a generic IOCTL function is supported
+            let supported = matches!(
+                cpu.cx as u8,
+                SET_DEVICE_PARAMETERS | SET_MEDIA_ID | GET_DEVICE_PARAMETERS | GET_MEDIA_ID
+            );
+            drive(data, cpu.bx)?;
+            if (cpu.cx >> 8) as u8 != CATEGORY_DISK || !supported {
+                return Err(DosError::InvalidFunction);
+            }
+            emu.reg_write(RegisterX86::AX, 0).unwrap();
+        }
+        // The devices don't take control strings (AL=02h-05h) and there is no generic
+        // IOCTL for character devices
+        _ => return Err(DosError::InvalidFunction),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use unicorn_engine::RegisterX86::{AX, BX, DX};
+
+    use super::{SECTORS, device_parameters};
+    use crate::{
+        dos::int21,
+        testing::{carry, emulator, set_regs},
+    };
+
+    #[test]
+    fn local_handles() {
+        let mut emu = emulator(&env::temp_dir());
+        set_regs(&mut emu, &[(AX, 0x440A), (BX, 1), (DX, 0xFFFF)]);
+        int21(&mut emu);
+        assert!(!carry(&emu));
+        assert_eq!(emu.reg_read(DX).unwrap(), 0);
+
+        set_regs(&mut emu, &[(AX, 0x440A), (BX, 19)]);
+        int21(&mut emu);
+        assert!(carry(&emu));
+        assert_eq!(emu.reg_read(AX).unwrap(), 0x06);
+    }
+
+    #[test]
+    fn fixed_disk_parameters() {
+        let block = device_parameters();
+        assert_eq!(block[1], 0x05);
+        assert_eq!(u16::from_le_bytes([block[4], block[5]]), 1040);
+        let bpb = &block[7..];
+        assert_eq!(u16::from_le_bytes([bpb[0], bpb[1]]), 512);
+        assert_eq!(bpb[0x0A], 0xF8);
+        assert_eq!(
+            u32::from_le_bytes(bpb[0x15..0x19].try_into().unwrap()),
+            SECTORS
+        );
+    }
+}
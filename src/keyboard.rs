@@ -0,0 +1,329 @@
+use std::{collections::VecDeque, fs, io, path::Path};
+
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    console::{BACKSPACE, CR, EOF},
+    dos::{abort, set_zero},
+    engine::{Cpu, EngineData},
+};
+
+/// Keyboard buffer variables in the BIOS data area, the head and tail are offsets from 0040h
+const BDA_SHIFT_FLAGS: u64 = 0x417;
+const BDA_HEAD: u64 = 0x41A;
+const BDA_TAIL: u64 = 0x41C;
+const BDA_BUFFER_START: u64 = 0x480;
+const BDA_BUFFER_END: u64 = 0x482;
+const BDA_SEGMENT: u64 = 0x40;
+/// The 16 word ring buffer at 0040:001E
+const BUFFER_START: u16 = 0x1E;
+const BUFFER_END: u16 = 0x3E;
+
+const ESC: u8 = 0x1B;
+
+/// Scripted keyboard input from `--keys`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// Scancode in the high byte and ASCII in the low byte, like INT 16h returns them
+    Key(u16),
+    /// Wait until the program has run this many instructions
+    Wait(u64),
+}
+
+/// Parse a key script, every line is either a key as hex scancode and ASCII like `1c 0d`
+/// or `wait N` with the number of instructions to wait. `#` starts a comment.
+pub fn parse_keys(script: &str) -> Result<Vec<KeyEvent>, String> {
+    let mut events = Vec::new();
+    for (idx, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = || format!("line {}: invalid key '{line}'", idx + 1);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let event = match parts.as_slice() {
+            ["wait", count] => KeyEvent::Wait(count.parse().map_err(|_| error())?),
+            [scancode, ascii] => {
+                let scancode = u8::from_str_radix(scancode, 16).map_err(|_| error())?;
+                let ascii = u8::from_str_radix(ascii, 16).map_err(|_| error())?;
+                KeyEvent::Key(u16::from_be_bytes([scancode, ascii]))
+            }
+            _ => return Err(error()),
+        };
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Scancodes of the US keyboard for ASCII characters, shifted characters share the key
+const SCANCODE_ROWS: [(&str, &str, u8); 4] = [
+    ("1234567890-=", "!@#$%^&*()_+", 0x02),
+    ("qwertyuiop[]", "QWERTYUIOP{}", 0x10),
+    ("asdfghjkl;'`", "ASDFGHJKL:\"~", 0x1E),
+    ("\\zxcvbnm,./", "|ZXCVBNM<>?", 0x2B),
+];
+
+/// Key for a character typed on the host terminal
+fn key_for_ascii(ascii: u8) -> u16 {
+    let scancode = match ascii {
+        ESC => 0x01,
+        BACKSPACE => 0x0E,
+        b'\t' => 0x0F,
+        CR => 0x1C,
+        b' ' => 0x39,
+        // Ctrl-A to Ctrl-Z are on the letter keys
+        0x01..=0x1A => return key_for_ascii(ascii + b'a' - 1) & 0xff00 | ascii as u16,
+        _ => SCANCODE_ROWS
+            .iter()
+            .find_map(|(plain, shifted, first)| {
+                let ch = ascii as char;
+                plain
+                    .find(ch)
+                    .or_else(|| shifted.find(ch))
+                    .map(|idx| first + idx as u8)
+            })
+            .unwrap_or(0),
+    };
+    u16::from_be_bytes([scancode, ascii])
+}
+
+/// Source of the keys for the BIOS keyboard buffer
+pub struct Keyboard {
+    script: VecDeque<KeyEvent>,
+    /// Instruction count when the next scripted key goes to the buffer
+    ready_at: u64,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            script: VecDeque::new(),
+            ready_at: 0,
+        }
+    }
+
+    /// Type the keys from a script instead of the host keyboard
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let script = fs::read_to_string(path)?;
+        let events =
+            parse_keys(&script).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Self {
+            script: events.into(),
+            ready_at: 0,
+        })
+    }
+
+    /// Next scripted key if its wait is over, `force` skips the wait because the
+    /// program is blocked waiting for a key anyway
+    fn next_key(&mut self, instructions: u64, force: bool) -> Option<u16> {
+        loop {
+            match self.script.front()? {
+                KeyEvent::Wait(count) => {
+                    self.ready_at = instructions + count;
+                    self.script.pop_front();
+                }
+                KeyEvent::Key(key) if force || instructions >= self.ready_at => {
+                    let key = *key;
+                    self.script.pop_front();
+                    return Some(key);
+                }
+                KeyEvent::Key(_) => return None,
+            }
+        }
+    }
+
+    fn scripted(&self) -> bool {
+        !self.script.is_empty()
+    }
+}
+
+fn read_word(emu: &Unicorn<EngineData>, addr: u64) -> u16 {
+    let mut buf = [0u8; 2];
+    emu.mem_read(addr, &mut buf).unwrap();
+    u16::from_le_bytes(buf)
+}
+
+fn write_word(emu: &mut Unicorn<EngineData>, addr: u64, value: u16) {
+    emu.mem_write(addr, &value.to_le_bytes()).unwrap();
+}
+
+fn next_offset(offset: u16) -> u16 {
+    if offset + 2 >= BUFFER_END {
+        BUFFER_START
+    } else {
+        offset + 2
+    }
+}
+
+/// Set up an empty keyboard buffer
+pub fn init(emu: &mut Unicorn<EngineData>) {
+    write_word(emu, BDA_HEAD, BUFFER_START);
+    write_word(emu, BDA_TAIL, BUFFER_START);
+    write_word(emu, BDA_BUFFER_START, BUFFER_START);
+    write_word(emu, BDA_BUFFER_END, BUFFER_END);
+    write_word(emu, BDA_SHIFT_FLAGS, 0);
+}
+
+/// Add a key to the end of the buffer, returns false if the buffer is full
+fn push_key(emu: &mut Unicorn<EngineData>, key: u16) -> bool {
+    let tail = read_word(emu, BDA_TAIL);
+    let next = next_offset(tail);
+    if next == read_word(emu, BDA_HEAD) {
+        return false;
+    }
+    write_word(emu, BDA_SEGMENT * 16 + tail as u64, key);
+    write_word(emu, BDA_TAIL, next);
+    true
+}
+
+fn peek_key(emu: &Unicorn<EngineData>) -> Option<u16> {
+    let head = read_word(emu, BDA_HEAD);
+    (head != read_word(emu, BDA_TAIL)).then(|| read_word(emu, BDA_SEGMENT * 16 + head as u64))
+}
+
+fn pop_key(emu: &mut Unicorn<EngineData>) -> Option<u16> {
+    let key = peek_key(emu)?;
+    let head = read_word(emu, BDA_HEAD);
+    write_word(emu, BDA_HEAD, next_offset(head));
+    Some(key)
+}
+
+/// Move the scripted keys whose wait is over to the buffer, called for every instruction
+pub fn tick(emu: &mut Unicorn<EngineData>) {
+    let data = emu.get_data();
+    if !data.keyboard.scripted() || data.instructions < data.keyboard.ready_at {
+        return;
+    }
+
+    let instructions = data.instructions;
+    while let Some(key) = emu.get_data_mut().keyboard.next_key(instructions, false) {
+        if !push_key(emu, key) {
+            // Keep the key until the program has read some from the full buffer
+            emu.get_data_mut()
+                .keyboard
+                .script
+                .push_front(KeyEvent::Key(key));
+            break;
+        }
+    }
+}
+
+/// Translate the arrow key escape sequences of the terminal, other keys are typed as they are
+fn read_host_key(emu: &mut Unicorn<EngineData>, wait: bool) -> Option<u16> {
+    let console = &mut emu.get_data_mut().console;
+    let ascii = if wait {
+        console.read()
+    } else {
+        console.try_read()?
+    };
+
+    if ascii == ESC && console.available() {
+        let mut sequence = vec![];
+        for _ in 0..2 {
+            sequence.extend(console.try_read());
+        }
+        let scancode = match sequence.as_slice() {
+            [b'[', b'A'] => 0x48,
+            [b'[', b'B'] => 0x50,
+            [b'[', b'C'] => 0x4D,
+            [b'[', b'D'] => 0x4B,
+            _ => return Some(key_for_ascii(ESC)),
+        };
+        return Some(u16::from_be_bytes([scancode, 0]));
+    }
+
+    Some(key_for_ascii(ascii))
+}
+
+/// Put a key to the buffer if one is available, `wait` blocks until there is one.
+///
+/// A blocked program gets the next scripted key right away and the host keyboard is
+/// used once the script has ended.
+fn fill_buffer(emu: &mut Unicorn<EngineData>, wait: bool) {
+    if peek_key(emu).is_some() {
+        return;
+    }
+
+    let data = emu.get_data_mut();
+    let key = if data.keyboard.scripted() {
+        let instructions = data.instructions;
+        data.keyboard.next_key(instructions, wait)
+    } else {
+        read_host_key(emu, wait)
+    };
+    if let Some(key) = key {
+        push_key(emu, key);
+    }
+}
+
+/// Handle keyboard BIOS calls (INT 16h)
+pub fn int16(emu: &mut Unicorn<EngineData>) {
+    let cpu = Cpu::read_engine(emu);
+    let ah = cpu.ax >> 8;
+    if ah == 0x00 || ah == 0x10 {
+        fill_buffer(emu, true);
+        // Ctrl-Z once the host input has ended so the program doesn't wait forever
+        let key = pop_key(emu).unwrap_or_else(|| key_for_ascii(EOF));
+        emu.reg_write(RegisterX86::AX, key as u64).unwrap();
+    } else if ah == 0x01 || ah == 0x11 {
+        fill_buffer(emu, false);
+        let key = peek_key(emu);
+        if let Some(key) = key {
+            emu.reg_write(RegisterX86::AX, key as u64).unwrap();
+        }
+        set_zero(emu, key.is_none());
+    } else if ah == 0x02 || ah == 0x12 {
+        let flags = read_word(emu, BDA_SHIFT_FLAGS);
+        let ax = if ah == 0x02 {
+            (cpu.ax & 0xff00) | (flags & 0xff) as u64
+        } else {
+            flags as u64
+        };
+        emu.reg_write(RegisterX86::AX, ax).unwrap();
+    } else if ah == 0x05 {
+        // Store a key in the buffer, AL is 1 if it is full
+        let full = !push_key(emu, cpu.cx as u16);
+        emu.reg_write(RegisterX86::AL, full as u64).unwrap();
+    } else {
+        abort(emu, &format!("Unimplemented ah for 0x16: 0x{ah:x}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyEvent, Keyboard, key_for_ascii, parse_keys};
+
+    #[test]
+    fn key_script() {
+        let script = "# press enter\n1c 0d\nwait 100\n01 1b # escape\n";
+        let events = parse_keys(script).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                KeyEvent::Key(0x1c0d),
+                KeyEvent::Wait(100),
+                KeyEvent::Key(0x011b)
+            ]
+        );
+        assert_eq!(parse_keys("1c\n").unwrap_err(), "line 1: invalid key '1c'");
+
+        let mut keyboard = Keyboard {
+            script: events.into(),
+            ready_at: 0,
+        };
+        assert_eq!(keyboard.next_key(10, false), Some(0x1c0d));
+        assert_eq!(keyboard.next_key(10, false), None);
+        assert_eq!(keyboard.next_key(109, false), None);
+        assert_eq!(keyboard.next_key(110, false), Some(0x011b));
+    }
+
+    #[test]
+    fn host_keys() {
+        assert_eq!(key_for_ascii(b'a'), 0x1e61);
+        assert_eq!(key_for_ascii(b'A'), 0x1e41);
+        assert_eq!(key_for_ascii(b'1'), 0x0231);
+        assert_eq!(key_for_ascii(0x0d), 0x1c0d);
+        assert_eq!(key_for_ascii(0x03), 0x2e03);
+    }
+}
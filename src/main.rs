@@ -1,17 +1,119 @@
+use std::{path::Path, process::exit};
+
 use clap::Parser;
 
-use crate::{debugger::Debugger, engine::Engine, program::Program};
+use crate::{
+    cli::CliArgs,
+    console::ConsoleInput,
+    debugger::Debugger,
+    engine::{Engine, FAILURE_MARKER, Failure},
+    keyboard::Keyboard,
+    program::Program,
+};
 
+mod bios;
 mod cli;
+mod clock;
+mod console;
 mod debugger;
+mod device;
+mod dos;
+mod drive;
 mod engine;
+mod environment;
+mod files;
+mod find;
+mod ioctl;
+mod keyboard;
+mod memory;
+mod ports;
+mod process;
 mod program;
+mod sandbox;
+#[cfg(test)]
+mod testing;
+mod timer;
+mod video;
+
+/// The program can't be started, exit with the emulator's own exit code so the error
+/// isn't mistaken for the program's
+fn setup_failed(args: &CliArgs, msg: String) -> ! {
+    eprintln!("{FAILURE_MARKER} {msg}");
+    exit(Failure::Setup.exit_code(args.emulator_exit_code));
+}
 
 fn main() {
-    let args = cli::CliArgs::parse();
-    let program = Program::new(&args.program_path, 0x1000);
-    let mut engine = Engine::new(program);
+    let args = CliArgs::parse();
+    let program = match Program::new(Path::new(&args.program_path), args.load_segment as u64) {
+        Ok(program) => program,
+        Err(err) => {
+            setup_failed(&args, format!("Cannot load '{}': {err}", args.program_path));
+        }
+    };
+
+    if args.dump_relocations {
+        println!("{} relocations:", program.fixups().len());
+        for fixup in program.fixups() {
+            println!("    {fixup}");
+        }
+        return;
+    }
+
+    let mut engine = match Engine::new(program, &args) {
+        Ok(engine) => engine,
+        Err(err) => setup_failed(&args, format!("Cannot load '{}': {err}", args.program_path)),
+    };
+    engine.set_failure_code(args.emulator_exit_code);
     engine.set_verbose(args.verbose);
+    engine.set_cp437(args.cp437);
+    engine.set_report(args.report);
+    if let Some(dir) = &args.overlay
+        && let Err(err) = engine.set_overlay(dir.clone())
+    {
+        setup_failed(
+            &args,
+            format!("Cannot use overlay '{}': {err}", dir.display()),
+        );
+    }
+    if let Some(path) = &args.keys {
+        match Keyboard::from_file(path) {
+            Ok(keyboard) => engine.set_keys(keyboard),
+            Err(err) => {
+                setup_failed(
+                    &args,
+                    format!("Cannot read keys '{}': {err}", path.display()),
+                );
+            }
+        }
+    }
+    if let Some(path) = &args.input {
+        match ConsoleInput::from_file(path) {
+            Ok(input) => engine.set_input(input),
+            Err(err) => {
+                setup_failed(
+                    &args,
+                    format!("Cannot read input '{}': {err}", path.display()),
+                );
+            }
+        }
+    }
+
+    if let Some(path) = &args.prn
+        && let Err(err) = engine.capture_printer(path)
+    {
+        setup_failed(
+            &args,
+            format!("Cannot write printer output '{}': {err}", path.display()),
+        );
+    }
+    if let Some(path) = &args.aux
+        && let Err(err) = engine.capture_aux(path)
+    {
+        setup_failed(
+            &args,
+            format!("Cannot write AUX output '{}': {err}", path.display()),
+        );
+    }
 
     if args.debug_mode() {
         let mut debug = Debugger::new(engine);
@@ -20,7 +122,9 @@ fn main() {
         } else {
             debug.repl();
         }
+        exit(debug.engine.finish());
     } else {
         engine.start();
+        exit(engine.finish());
     }
 }
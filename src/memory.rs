@@ -0,0 +1,325 @@
+use unicorn_engine::Unicorn;
+
+use crate::{engine::EngineData, files::DosError};
+
+/// Segment of the first memory control block, everything below belongs to the "system"
+pub const FIRST_MCB: u16 = 0x0700;
+/// End of conventional memory (640K)
+pub const MEMORY_TOP: u16 = 0xA000;
+
+/// Paragraphs owned by nobody
+const FREE: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Block {
+    /// Segment of the MCB, the memory itself starts at the next paragraph
+    mcb: u16,
+    /// Size in paragraphs without the MCB
+    size: u16,
+    /// PSP segment of the owner, 0 if the block is free
+    owner: u16,
+    /// Program name stored in the MCB since DOS 4.0
+    name: [u8; 8],
+}
+
+impl Block {
+    fn segment(&self) -> u16 {
+        self.mcb + 1
+    }
+
+    fn end(&self) -> u16 {
+        self.segment() + self.size
+    }
+
+    fn is_free(&self) -> bool {
+        self.owner == FREE
+    }
+}
+
+/// Failed allocation with the size of the largest block that would have fit, returned in BX
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    pub error: DosError,
+    pub largest: u16,
+}
+
+/// Conventional memory split into blocks the same way MsDos chains its MCBs.
+///
+/// The blocks are kept here and written out to the emulated memory after every change
+/// so programs walking the MCB chain see the same layout.
+pub struct MemoryArena {
+    blocks: Vec<Block>,
+}
+
+impl MemoryArena {
+    pub fn new(first: u16, top: u16) -> Self {
+        Self {
+            blocks: vec![Block {
+                mcb: first,
+                size: top - first - 1,
+                owner: FREE,
+                name: [0; 8],
+            }],
+        }
+    }
+
+    fn find(&self, segment: u16) -> Result<usize, DosError> {
+        self.blocks
+            .iter()
+            .position(|block| block.segment() == segment && !block.is_free())
+            .ok_or(DosError::InvalidMemoryBlock)
+    }
+
+    /// Split the block so it is `size` paragraphs and the rest becomes a free block
+    fn split(&mut self, idx: usize, size: u16) {
+        let block = self.blocks[idx];
+        if block.size > size {
+            self.blocks[idx].size = size;
+            self.blocks.insert(
+                idx + 1,
+                Block {
+                    mcb: block.segment() + size,
+                    size: block.size - size - 1,
+                    owner: FREE,
+                    name: [0; 8],
+                },
+            );
+        }
+    }
+
+    /// Join free blocks that are next to each other
+    fn merge_free(&mut self) {
+        let mut idx = 0;
+        while idx + 1 < self.blocks.len() {
+            if self.blocks[idx].is_free() && self.blocks[idx + 1].is_free() {
+                let next = self.blocks.remove(idx + 1);
+                self.blocks[idx].size += next.size + 1;
+            } else {
+                idx += 1;
+            }
+        }
+    }
+
+    pub fn largest_free(&self) -> u16 {
+        self.blocks
+            .iter()
+            .filter(|block| block.is_free())
+            .map(|block| block.size)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Allocate memory with first fit strategy (AH=48h), returns the segment of the block
+    pub fn allocate(&mut self, size: u16, owner: u16) -> Result<u16, AllocError> {
+        let idx = self
+            .blocks
+            .iter()
+            .position(|block| block.is_free() && block.size >= size)
+            .ok_or(AllocError {
+                error: DosError::InsufficientMemory,
+                largest: self.largest_free(),
+            })?;
+
+        self.split(idx, size);
+        self.blocks[idx].owner = owner;
+        Ok(self.blocks[idx].segment())
+    }
+
+    /// Allocate a block that starts from the given segment, used when loading programs.
+    ///
+    /// Gives at least `min` and at most `max` paragraphs and returns the size that was allocated.
+    pub fn allocate_at(
+        &mut self,
+        segment: u16,
+        min: u16,
+        max: u16,
+        owner: u16,
+        name: &str,
+    ) -> Result<u16, AllocError> {
+        let no_memory = AllocError {
+            error: DosError::InsufficientMemory,
+            largest: self.largest_free(),
+        };
+        let mcb = segment.checked_sub(1).ok_or(no_memory)?;
+        let idx = self
+            .blocks
+            .iter()
+            .position(|block| block.is_free() && block.mcb <= mcb && mcb < block.end())
+            .ok_or(no_memory)?;
+
+        // Leave the start of the free block as its own block
+        let mut idx = idx;
+        if self.blocks[idx].mcb < mcb {
+            let gap = mcb - self.blocks[idx].segment();
+            self.split(idx, gap);
+            idx += 1;
+        }
+
+        let available = self.blocks[idx].size;
+        if available < min {
+            return Err(AllocError {
+                error: DosError::InsufficientMemory,
+                largest: available,
+            });
+        }
+
+        let size = max.min(available);
+        self.split(idx, size);
+        let block = &mut self.blocks[idx];
+        block.owner = owner;
+        for (dst, src) in block.name.iter_mut().zip(name.bytes()) {
+            *dst = src;
+        }
+        Ok(size)
+    }
+
+    /// Give a block to another owner, the name is shown by tools that walk the MCB chain
+    pub fn assign(&mut self, segment: u16, owner: u16, name: &str) {
+        if let Ok(idx) = self.find(segment) {
+            let block = &mut self.blocks[idx];
+            block.owner = owner;
+            block.name = [0; 8];
+            for (dst, src) in block.name.iter_mut().zip(name.bytes()) {
+                *dst = src;
+            }
+        }
+    }
+
+    /// Release a block (AH=49h)
+    pub fn free(&mut self, segment: u16) -> Result<(), DosError> {
+        let idx = self.find(segment)?;
+        self.blocks[idx].owner = FREE;
+        self.blocks[idx].name = [0; 8];
+        self.merge_free();
+        Ok(())
+    }
+
+    /// Release every block owned by a program when it terminates
+    pub fn free_owned(&mut self, owner: u16) {
+        for block in self.blocks.iter_mut().filter(|block| block.owner == owner) {
+            block.owner = FREE;
+            block.name = [0; 8];
+        }
+        self.merge_free();
+    }
+
+    /// Grow or shrink a block in place (AH=4Ah)
+    pub fn resize(&mut self, segment: u16, size: u16) -> Result<(), AllocError> {
+        let idx = self
+            .find(segment)
+            .map_err(|error| AllocError { error, largest: 0 })?;
+
+        let current = self.blocks[idx].size;
+        let next_free = self
+            .blocks
+            .get(idx + 1)
+            .filter(|block| block.is_free())
+            .map_or(0, |block| block.size + 1);
+        let max = current + next_free;
+
+        if size > max {
+            return Err(AllocError {
+                error: DosError::InsufficientMemory,
+                largest: max,
+            });
+        }
+
+        if next_free > 0 {
+            let next = self.blocks.remove(idx + 1);
+            self.blocks[idx].size += next.size + 1;
+        }
+        self.split(idx, size);
+        self.merge_free();
+        Ok(())
+    }
+
+    /// The MCBs as they are laid out in memory, linear address and the 16 header bytes
+    pub fn headers(&self) -> Vec<(u64, [u8; 16])> {
+        let last = self.blocks.len() - 1;
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| {
+                let mut mcb = [0u8; 16];
+                mcb[0] = if idx == last { b'Z' } else { b'M' };
+                mcb[1..3].copy_from_slice(&block.owner.to_le_bytes());
+                mcb[3..5].copy_from_slice(&block.size.to_le_bytes());
+                mcb[8..16].copy_from_slice(&block.name);
+                (block.mcb as u64 * 16, mcb)
+            })
+            .collect()
+    }
+}
+
+/// Write the MCB chain into the emulated memory
+pub fn write_chain(emu: &mut Unicorn<EngineData>) {
+    for (addr, mcb) in emu.get_data().memory.headers() {
+        emu.mem_write(addr, &mcb).unwrap();
+    }
+}
+
+/// Check that the program hasn't overwritten the MCB signatures
+pub fn chain_intact(emu: &Unicorn<EngineData>) -> bool {
+    emu.get_data()
+        .memory
+        .headers()
+        .iter()
+        .all(|(addr, mcb)| emu.mem_read_as_vec(*addr, 1).unwrap()[0] == mcb[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocError, MemoryArena};
+    use crate::files::DosError;
+
+    fn signatures(arena: &MemoryArena) -> Vec<(u64, u8)> {
+        arena
+            .headers()
+            .iter()
+            .map(|(addr, mcb)| (*addr / 16, mcb[0]))
+            .collect()
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let mut arena = MemoryArena::new(0x100, 0x1000);
+        let a = arena.allocate(0x10, 0x50).unwrap();
+        let b = arena.allocate(0x20, 0x50).unwrap();
+        assert_eq!(a, 0x101);
+        assert_eq!(b, 0x112);
+        assert_eq!(
+            signatures(&arena),
+            vec![(0x100, b'M'), (0x111, b'M'), (0x132, b'Z')]
+        );
+
+        arena.free(a).unwrap();
+        assert_eq!(arena.free(a), Err(DosError::InvalidMemoryBlock));
+        arena.free(b).unwrap();
+        assert_eq!(signatures(&arena), vec![(0x100, b'Z')]);
+        assert_eq!(arena.largest_free(), 0x1000 - 0x100 - 1);
+    }
+
+    #[test]
+    fn resize_and_errors() {
+        let mut arena = MemoryArena::new(0x100, 0x200);
+        let size = arena
+            .allocate_at(0x150, 0x10, 0xffff, 0x150, "PROG")
+            .unwrap();
+        assert_eq!(size, 0x200 - 0x150);
+        assert_eq!(arena.largest_free(), 0x4e);
+
+        arena.resize(0x150, 0x20).unwrap();
+        let err = arena.allocate(0x100, 0x150).unwrap_err();
+        assert_eq!(
+            err,
+            AllocError {
+                error: DosError::InsufficientMemory,
+                largest: 0x8f,
+            }
+        );
+        assert_eq!(
+            arena.resize(0x150, 0xc0).unwrap_err().largest,
+            0x200 - 0x150
+        );
+    }
+}
@@ -0,0 +1,433 @@
+use std::{collections::HashSet, ops::RangeInclusive};
+
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    bios::{self, INTERRUPT_FLAG},
+    engine::{EngineData, FarPointer},
+};
+
+/// Hardware that answers IN and OUT instructions on its ports.
+///
+/// `now` is the number of instructions run so far, the emulator's only notion of time.
+pub trait PortDevice {
+    fn name(&self) -> &'static str;
+
+    /// Port ranges the device answers on
+    fn ports(&self) -> &[RangeInclusive<u16>];
+
+    fn read(&mut self, port: u16, now: u64) -> u8;
+
+    fn write(&mut self, port: u16, value: u8, now: u64);
+
+    /// IRQ line the device is connected to on the PIC
+    fn irq_line(&self) -> Option<u8> {
+        None
+    }
+
+    /// The device wants to raise its IRQ
+    fn irq_pending(&self, _now: u64) -> bool {
+        false
+    }
+
+    /// The IRQ was delivered to the cpu
+    fn irq_taken(&mut self, _now: u64) {}
+}
+
+/// Master 8259 PIC, IRQs 0-7 go to INT 08h-0Fh
+pub struct Pic {
+    /// Interrupt mask register, a set bit disables the IRQ
+    mask: u8,
+    /// IRQs being serviced until the handler sends the end of interrupt
+    in_service: u8,
+    /// OCW3 selected the in service register for reads from port 20h
+    read_isr: bool,
+}
+
+const PIC_COMMAND: u16 = 0x20;
+const PIC_DATA: u16 = 0x21;
+const PIC_VECTOR_BASE: u8 = 0x08;
+const EOI: u8 = 0x20;
+
+impl Pic {
+    fn new() -> Self {
+        Self {
+            mask: 0,
+            in_service: 0,
+            read_isr: false,
+        }
+    }
+
+    /// Clear the highest priority IRQ in service
+    pub fn end_of_interrupt(&mut self) {
+        self.in_service &= self.in_service.wrapping_sub(1);
+    }
+}
+
+impl PortDevice for Pic {
+    fn name(&self) -> &'static str {
+        "PIC"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &[PIC_COMMAND..=PIC_DATA]
+    }
+
+    fn read(&mut self, port: u16, _now: u64) -> u8 {
+        match port {
+            PIC_DATA => self.mask,
+            _ if self.read_isr => self.in_service,
+            // Nothing waits in the request register, IRQs are delivered as soon as they happen
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8, _now: u64) {
+        match port {
+            PIC_DATA => self.mask = value,
+            // Non specific and specific EOI
+            _ if value & 0xE0 == EOI => self.end_of_interrupt(),
+            _ if value & 0x60 == 0x60 => self.in_service &= !(1 << (value & 7)),
+            // OCW3, select the register read from the command port
+            _ if value & 0x18 == 0x08 && value & 0x02 != 0 => self.read_isr = value & 1 != 0,
+            // ICW1 starts the initialization, the BIOS setup is kept
+            _ => {}
+        }
+    }
+}
+
+/// 8042 keyboard controller and the system control port 61h
+pub struct KeyboardController {
+    port_b: u8,
+}
+
+impl PortDevice for KeyboardController {
+    fn name(&self) -> &'static str {
+        "keyboard controller"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &[0x60..=0x61, 0x64..=0x64]
+    }
+
+    fn read(&mut self, port: u16, _now: u64) -> u8 {
+        match port {
+            0x61 => {
+                // Bit 4 toggles with the memory refresh, delay loops wait for it to flip
+                self.port_b ^= 0x10;
+                self.port_b
+            }
+            // System flag set and the keyboard isn't inhibited, output buffer empty
+            0x64 => 0x14,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8, _now: u64) {
+        if port == 0x61 {
+            self.port_b = value;
+        }
+    }
+}
+
+/// VGA registers, enough for programs that wait for the retrace or set the palette
+pub struct Vga {
+    crtc_index: u8,
+    crtc: [u8; 0x19],
+    dac_write: u16,
+    dac_read: u16,
+    palette: [u8; 768],
+    retrace: bool,
+}
+
+impl PortDevice for Vga {
+    fn name(&self) -> &'static str {
+        "VGA"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &[0x3C0..=0x3DF]
+    }
+
+    fn read(&mut self, port: u16, _now: u64) -> u8 {
+        match port {
+            0x3D5 => self
+                .crtc
+                .get(self.crtc_index as usize)
+                .copied()
+                .unwrap_or(0),
+            0x3C9 => {
+                let value = self.palette[self.dac_read as usize % 768];
+                self.dac_read = (self.dac_read + 1) % 768;
+                value
+            }
+            // Input status, the retrace bits flip on every read so waiting loops finish
+            0x3DA => {
+                self.retrace = !self.retrace;
+                if self.retrace { 0x09 } else { 0x00 }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8, _now: u64) {
+        match port {
+            0x3D4 => self.crtc_index = value,
+            0x3D5 => {
+                if let Some(register) = self.crtc.get_mut(self.crtc_index as usize) {
+                    *register = value;
+                }
+            }
+            0x3C7 => self.dac_read = value as u16 * 3,
+            0x3C8 => self.dac_write = value as u16 * 3,
+            0x3C9 => {
+                self.palette[self.dac_write as usize % 768] = value & 0x3f;
+                self.dac_write = (self.dac_write + 1) % 768;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// CMOS memory of the AT, the registers are selected through port 70h
+pub struct Cmos {
+    index: u8,
+    registers: [u8; 128],
+}
+
+impl Cmos {
+    fn new() -> Self {
+        let mut registers = [0; 128];
+        // 32768 Hz time base, 24 hour BCD time and the battery is good
+        registers[0x0A] = 0x26;
+        registers[0x0B] = 0x02;
+        registers[0x0D] = 0x80;
+        // 640K of base memory
+        registers[0x15] = 0x80;
+        registers[0x16] = 0x02;
+        Self {
+            index: 0,
+            registers,
+        }
+    }
+}
+
+impl PortDevice for Cmos {
+    fn name(&self) -> &'static str {
+        "CMOS"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &[0x70..=0x71]
+    }
+
+    fn read(&mut self, port: u16, _now: u64) -> u8 {
+        match port {
+            0x71 => self.registers[self.index as usize],
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8, _now: u64) {
+        match port {
+            // Bit 7 of the index disables NMI
+            0x70 => self.index = value & 0x7f,
+            _ => self.registers[self.index as usize] = value,
+        }
+    }
+}
+
+/// Game port without a joystick, the axes never time out and no buttons are pressed
+pub struct GamePort;
+
+impl PortDevice for GamePort {
+    fn name(&self) -> &'static str {
+        "game port"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &[0x201..=0x201]
+    }
+
+    fn read(&mut self, _port: u16, _now: u64) -> u8 {
+        0xf0
+    }
+
+    fn write(&mut self, _port: u16, _value: u8, _now: u64) {}
+}
+
+/// Devices on the I/O bus by the ports they answer on
+pub struct Ports {
+    pub pic: Pic,
+    devices: Vec<Box<dyn PortDevice>>,
+    /// Print every port access
+    pub log: bool,
+    /// Stop the emulation when these ports are accessed
+    pub breaks: HashSet<u16>,
+    /// Unknown ports that have been reported already
+    reported: HashSet<u16>,
+}
+
+impl Ports {
+    /// Bus with the devices of an AT, the PIT is registered by the engine
+    pub fn new() -> Self {
+        let mut ports = Self {
+            pic: Pic::new(),
+            devices: vec![],
+            log: false,
+            breaks: HashSet::new(),
+            reported: HashSet::new(),
+        };
+        ports.register(Box::new(KeyboardController { port_b: 0 }));
+        ports.register(Box::new(Vga {
+            crtc_index: 0,
+            crtc: [0; 0x19],
+            dac_write: 0,
+            dac_read: 0,
+            palette: [0; 768],
+            retrace: false,
+        }));
+        ports.register(Box::new(Cmos::new()));
+        ports.register(Box::new(GamePort));
+        ports
+    }
+
+    pub fn register(&mut self, device: Box<dyn PortDevice>) {
+        self.devices.push(device);
+    }
+
+    fn device(&mut self, port: u16) -> Option<&mut (dyn PortDevice + 'static)> {
+        if self.pic.ports().iter().any(|range| range.contains(&port)) {
+            return Some(&mut self.pic);
+        }
+        self.devices
+            .iter_mut()
+            .find(|device| device.ports().iter().any(|range| range.contains(&port)))
+            .map(|device| device.as_mut())
+    }
+
+    /// Read a byte, unknown ports float high
+    pub fn read(&mut self, port: u16, now: u64) -> Option<u8> {
+        self.device(port).map(|device| device.read(port, now))
+    }
+
+    /// Write a byte, returns false if there is no device on the port
+    pub fn write(&mut self, port: u16, value: u8, now: u64) -> bool {
+        self.device(port)
+            .map(|device| device.write(port, value, now))
+            .is_some()
+    }
+
+    /// Name of the device on the port for the log
+    fn name(&mut self, port: u16) -> &'static str {
+        self.device(port).map_or("unknown", |device| device.name())
+    }
+
+    /// Vector of an IRQ that is ready to be delivered, only one IRQ is serviced at a time
+    pub fn poll_irq(&mut self, now: u64) -> Option<u8> {
+        if self.pic.in_service != 0 {
+            return None;
+        }
+
+        let mask = self.pic.mask;
+        let device = self.devices.iter_mut().find(|device| {
+            device
+                .irq_line()
+                .is_some_and(|line| mask & (1 << line) == 0 && device.irq_pending(now))
+        })?;
+        let line = device.irq_line()?;
+        device.irq_taken(now);
+        self.pic.in_service |= 1 << line;
+        Some(PIC_VECTOR_BASE + line)
+    }
+}
+
+/// Log the access, stop on port breakpoints and report ports nothing answers on
+fn trace(emu: &mut Unicorn<EngineData>, port: u16, value: u8, write: bool, known: bool) {
+    let ports = &mut emu.get_data_mut().ports;
+    let direction = if write { "out" } else { "in" };
+    if ports.log {
+        let name = ports.name(port);
+        let fp = FarPointer::read_engine(emu);
+        println!("[{fp}] {direction} port 0x{port:x} ({name}): 0x{value:02x}");
+    }
+
+    let ports = &mut emu.get_data_mut().ports;
+    if !known && ports.reported.insert(port) {
+        eprintln!("Unknown I/O port 0x{port:x} ({direction}), the access is ignored");
+    }
+    if ports.breaks.contains(&port) {
+        let fp = FarPointer::read_engine(emu);
+        println!("breaking on {direction} port 0x{port:x} at [{fp}]");
+        emu.emu_stop().unwrap();
+    }
+}
+
+/// IN instruction, word reads are two byte reads from consecutive ports
+pub fn port_in(emu: &mut Unicorn<EngineData>, port: u32, size: usize) -> u32 {
+    let now = emu.get_data().instructions;
+    let mut value = 0;
+    for idx in 0..size.min(4) {
+        let port = (port as u16).wrapping_add(idx as u16);
+        let read = emu.get_data_mut().ports.read(port, now);
+        let byte = read.unwrap_or(0xff);
+        trace(emu, port, byte, false, read.is_some());
+        value |= (byte as u32) << (idx * 8);
+    }
+    value
+}
+
+/// OUT instruction, word writes go to consecutive ports low byte first
+pub fn port_out(emu: &mut Unicorn<EngineData>, port: u32, size: usize, value: u32) {
+    let now = emu.get_data().instructions;
+    for idx in 0..size.min(4) {
+        let port = (port as u16).wrapping_add(idx as u16);
+        let byte = (value >> (idx * 8)) as u8;
+        let known = emu.get_data_mut().ports.write(port, byte, now);
+        trace(emu, port, byte, true, known);
+    }
+}
+
+/// Deliver a pending IRQ through its vector when interrupts are enabled, called before
+/// every instruction
+pub fn tick(emu: &mut Unicorn<EngineData>) {
+    let flags = emu.reg_read(RegisterX86::EFLAGS).unwrap();
+    if flags & INTERRUPT_FLAG == 0 {
+        return;
+    }
+
+    let data = emu.get_data_mut();
+    let now = data.instructions;
+    if let Some(vector) = data.ports.poll_irq(now) {
+        let (segment, offset) = bios::vector(emu, vector);
+        bios::deliver(emu, segment, offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PIC_COMMAND, PIC_DATA, Ports};
+    use crate::timer::{PIT_HZ, Pit};
+
+    #[test]
+    fn irq_through_pic() {
+        let mut ports = Ports::new();
+        ports.register(Box::new(Pit::new(PIT_HZ)));
+        assert_eq!(ports.read(0x201, 0), Some(0xf0));
+        assert_eq!(ports.read(0x300, 0), None);
+
+        assert_eq!(ports.poll_irq(0xffff), None);
+        assert_eq!(ports.poll_irq(0x10000), Some(0x08));
+        // Nothing is delivered until the handler sends the EOI
+        assert_eq!(ports.poll_irq(0x20000), None);
+        ports.write(PIC_COMMAND, 0x20, 0x20000);
+        assert_eq!(ports.poll_irq(0x20000), Some(0x08));
+
+        // Masked IRQ0 stays pending until it is unmasked
+        ports.write(PIC_COMMAND, 0x20, 0x30000);
+        ports.write(PIC_DATA, 0x01, 0x30000);
+        assert_eq!(ports.poll_irq(0x30000), None);
+        ports.write(PIC_DATA, 0x00, 0x30000);
+        assert_eq!(ports.poll_irq(0x30000), Some(0x08));
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::read;
+
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    dos::{read_path, set_carry},
+    engine::{Cpu, EngineData, ExitStatus},
+    environment::Environment,
+    files::DosError,
+    memory,
+    program::{Format, PSP, Program},
+};
+
+/// Program that has been started, the first one is the program given on the command line
+pub struct Process {
+    pub psp_segment: u16,
+    /// DOS path of the program
+    pub path: String,
+    /// Disk transfer area as segment and offset, PSP:0080h until the program sets it
+    pub dta: (u16, u16),
+}
+
+fn read_u16(emu: &Unicorn<EngineData>, addr: u64) -> u16 {
+    let mut buf = [0u8; 2];
+    emu.mem_read(addr, &mut buf).unwrap();
+    u16::from_le_bytes(buf)
+}
+
+/// Read a far pointer stored as offset followed by segment
+fn read_far(emu: &Unicorn<EngineData>, addr: u64) -> (u16, u16) {
+    (read_u16(emu, addr + 2), read_u16(emu, addr))
+}
+
+/// Load and optionally run a child program (AH=4Bh AL=00h and 01h).
+///
+/// The parameter block at ES:BX has the environment segment and far pointers to the
+/// command tail and the two FCBs. When the program isn't run, its initial SS:SP and CS:IP
+/// are written after them like MsDos does for debuggers.
+pub fn exec(emu: &mut Unicorn<EngineData>, cpu: &Cpu, run: bool) -> Result<(), DosError> {
+    let file_name = read_path(emu, cpu.ds * 16 + cpu.dx)?;
+    let block = cpu.es * 16 + cpu.bx;
+    let env_param = read_u16(emu, block);
+    let (tail_seg, tail_off) = read_far(emu, block + 2);
+    let (fcb_1_seg, fcb_1_off) = read_far(emu, block + 6);
+    let (fcb_2_seg, fcb_2_off) = read_far(emu, block + 0x0A);
+
+    let drives = &emu.get_data().drives;
+    let host = drives.resolve(&file_name)?;
+    let dos_path = drives.dos_path(&host).unwrap_or(file_name.clone());
+    let bytes = read(&host).map_err(|err| DosError::for_path(err, &host))?;
+    // The program has to be parsed before it's loaded to know how much memory it needs
+    let (min, max) = Program::from_bytes(bytes.clone(), 0)?.allocation()?;
+
+    // The child gets a copy of the parent's environment unless it is given one
+    let parent = emu.get_data().psp_segment;
+    let env_source = match env_param {
+        0 => read_u16(emu, parent as u64 * 16 + PSP::ENV_SEGMENT),
+        segment => segment,
+    };
+    let env_block = emu.mem_read_as_vec(env_source as u64 * 16, 0x8000).unwrap();
+    let env = Environment::from_block(&env_block, &dos_path);
+
+    let memory = &mut emu.get_data_mut().memory;
+    let env_segment = memory
+        .allocate(env.paragraphs(), parent)
+        .map_err(|err| err.error)?;
+    let largest = memory.largest_free();
+    if largest < min {
+        memory.free(env_segment)?;
+        return Err(DosError::InsufficientMemory);
+    }
+    let size = max.min(largest);
+    let psp_segment = memory.allocate(size, parent).map_err(|err| err.error)?;
+
+    let program = match Program::from_bytes(bytes, psp_segment as u64 + 0x10) {
+        Ok(program) => program,
+        Err(err) => {
+            memory.free(psp_segment)?;
+            memory.free(env_segment)?;
+            return Err(err.into());
+        }
+    };
+
+    let name = host
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().to_uppercase());
+    memory.assign(env_segment, psp_segment, "");
+    memory.assign(psp_segment, psp_segment, &name);
+    memory::write_chain(emu);
+
+    let tail_addr = tail_seg as u64 * 16 + tail_off as u64;
+    let tail_len = emu.mem_read_as_vec(tail_addr, 1).unwrap()[0];
+    let tail = emu
+        .mem_read_as_vec(tail_addr + 1, tail_len as usize)
+        .unwrap();
+    let mut fcb_1 = [0u8; 16];
+    let mut fcb_2 = [0u8; 16];
+    emu.mem_read(fcb_1_seg as u64 * 16 + fcb_1_off as u64, &mut fcb_1)
+        .unwrap();
+    emu.mem_read(fcb_2_seg as u64 * 16 + fcb_2_off as u64, &mut fcb_2)
+        .unwrap();
+
+    let mut psp = PSP::new(psp_segment + size, 0x0, env_segment, &[]);
+    psp.set_command_tail(&tail);
+    psp.set_fcbs(fcb_1, fcb_2);
+    // The child returns to the instruction after the INT 21h
+    psp.set_parent(parent, cpu.cs as u16, cpu.ip as u16);
+    let psp_data: &[u8] = (&psp).into();
+    let psp_addr = psp_segment as u64 * 16;
+    emu.mem_write(psp_addr, psp_data).unwrap();
+    emu.mem_write(env_segment as u64 * 16, &env.to_bytes())
+        .unwrap();
+
+    let load_addr = psp_addr + 0x100;
+    emu.mem_write(load_addr, program.data()).unwrap();
+    emu.ctl_remove_cache(load_addr, load_addr + program.data().len() as u64)
+        .unwrap();
+
+    // The parent's stack is restored from its PSP when the child terminates
+    let parent_stack = ((cpu.ss as u32) << 16) | cpu.sp as u32;
+    emu.mem_write(
+        parent as u64 * 16 + PSP::STACK_SAVE,
+        &parent_stack.to_le_bytes(),
+    )
+    .unwrap();
+
+    let (cs, ip) = program.entry();
+    let (ss, sp) = program.stack();
+    if let Format::Com = program.format() {
+        emu.mem_write(ss * 16 + sp, &[0, 0]).unwrap();
+    }
+
+    if emu.get_data().verbose {
+        println!("Starting {dos_path} with PSP at {psp_segment:04x}");
+    }
+    let data = emu.get_data_mut();
+    data.psp_segment = psp_segment;
+    data.files.inherit();
+    data.processes.push(Process {
+        psp_segment,
+        path: dos_path,
+        dta: (psp_segment, 0x80),
+    });
+
+    if run {
+        emu.reg_write(RegisterX86::SS, ss).unwrap();
+        emu.reg_write(RegisterX86::SP, sp).unwrap();
+        emu.reg_write(RegisterX86::DS, psp_segment as u64).unwrap();
+        emu.reg_write(RegisterX86::ES, psp_segment as u64).unwrap();
+        emu.reg_write(RegisterX86::AX, 0).unwrap();
+        emu.reg_write(RegisterX86::CS, cs).unwrap();
+        emu.reg_write(RegisterX86::IP, ip).unwrap();
+    } else {
+        // AX is pushed on the child's stack for the debugger, SP=0 is a full 64K stack
+        let sp = sp.wrapping_sub(2) & 0xffff;
+        emu.mem_write(ss * 16 + sp, &[0, 0]).unwrap();
+        let mut regs = Vec::new();
+        for value in [sp, ss, ip, cs] {
+            regs.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        emu.mem_write(block + 0x0E, &regs).unwrap();
+    }
+
+    Ok(())
+}
+
+/// End the running program with an exit code (AH=4Ch, AH=00h and INT 20h).
+///
+/// Child programs return to their parent, when the first program terminates the emulation stops.
+pub fn terminate(emu: &mut Unicorn<EngineData>, code: u8) {
+    if emu.get_data().processes.len() <= 1 {
+        // Only in verbose mode, so the program's output can be compared as is
+        if emu.get_data().verbose {
+            println!("Program terminating with code '0x{code:x}', exiting...");
+        }
+        emu.get_data_mut().exit = Some(ExitStatus::Terminated(code));
+        emu.emu_stop().unwrap();
+        return;
+    }
+
+    let child = emu.get_data_mut().processes.pop().unwrap();
+    let psp_addr = child.psp_segment as u64 * 16;
+    let parent = read_u16(emu, psp_addr + PSP::PARENT);
+    let (term_cs, term_ip) = read_far(emu, psp_addr + PSP::TERM_ADDR);
+    let (ss, sp) = read_far(emu, parent as u64 * 16 + PSP::STACK_SAVE);
+
+    if emu.get_data().verbose {
+        println!(
+            "{} terminating with code '0x{code:x}', returning to {term_cs:04x}:{term_ip:04x}",
+            child.path
+        );
+    }
+
+    let data = emu.get_data_mut();
+    // The exit type in AH is 0 for a normal termination
+    data.return_code = Some(code as u16);
+    data.psp_segment = parent;
+    data.memory.free_owned(child.psp_segment);
+    let now = data.clock.system_time(data.instructions);
+    data.files.exit(now);
+    memory::write_chain(emu);
+
+    emu.reg_write(RegisterX86::SS, ss as u64).unwrap();
+    emu.reg_write(RegisterX86::SP, sp as u64).unwrap();
+    emu.reg_write(RegisterX86::CS, term_cs as u64).unwrap();
+    emu.reg_write(RegisterX86::IP, term_ip as u64).unwrap();
+    set_carry(emu, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use unicorn_engine::RegisterX86::{AX, BX, DS, DX, ES};
+
+    use crate::{
+        dos::int21,
+        testing::{TempDir, carry, emulator, set_regs},
+    };
+
+    /// EXE with a 16 byte load module and the given initial SP
+    fn exe(sp: u16) -> Vec<u8> {
+        let mut exe = vec![0u8; 0x30];
+        exe[0..2].copy_from_slice(b"MZ");
+        exe[2..4].copy_from_slice(&0x30u16.to_le_bytes());
+        exe[4..6].copy_from_slice(&1u16.to_le_bytes());
+        exe[8..10].copy_from_slice(&2u16.to_le_bytes());
+        exe[12..14].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        exe[16..18].copy_from_slice(&sp.to_le_bytes());
+        exe[24..26].copy_from_slice(&0x1Cu16.to_le_bytes());
+        exe
+    }
+
+    #[test]
+    fn load_without_running() {
+        let dir = TempDir::new("exec-test");
+        fs::write(dir.0.join("CHILD.EXE"), exe(0)).unwrap();
+        let mut emu = emulator(&dir.0);
+        // The parent gives back the memory it doesn't need for the child
+        emu.get_data_mut().memory.resize(0x0FF0, 0x100).unwrap();
+
+        emu.mem_write(0x10100, b"CHILD.EXE\0").unwrap();
+        // Environment of the parent, the command tail and the FCBs at 1000:0120
+        let mut block = [0u8; 0x16];
+        for far in [2, 6, 0x0A] {
+            block[far..far + 2].copy_from_slice(&0x120u16.to_le_bytes());
+            block[far + 2..far + 4].copy_from_slice(&0x1000u16.to_le_bytes());
+        }
+        emu.mem_write(0x10180, &block).unwrap();
+        set_regs(
+            &mut emu,
+            &[
+                (AX, 0x4B01),
+                (DS, 0x1000),
+                (DX, 0x100),
+                (ES, 0x1000),
+                (BX, 0x180),
+            ],
+        );
+        int21(&mut emu);
+        assert!(!carry(&emu));
+
+        // SS:SP after the pushed AX, the full 64K stack of SP=0 wraps around
+        let regs = emu.mem_read_as_vec(0x10180 + 0x0E, 4).unwrap();
+        assert_eq!(u16::from_le_bytes([regs[0], regs[1]]), 0xFFFE);
+    }
+}
@@ -1,33 +1,187 @@
 use byteorder::{ByteOrder, LittleEndian};
-use std::fs::read;
+use std::{fmt::Display, fs::read, io, path::Path};
+
+use crate::files::DosError;
+
+/// Why a program couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    EmptyFile,
+    /// .COM programs have to fit in one segment with the PSP and a stack word
+    ComTooLarge(usize),
+    /// File is shorter than the fixed part of the MZ header
+    TruncatedHeader(usize),
+    /// Header size in paragraphs doesn't cover the fixed header or runs past the file
+    InvalidHeaderSize(u16),
+    /// Relocation table doesn't fit inside the header
+    RelocationTableOutOfBounds {
+        addr: u16,
+        count: u16,
+    },
+    /// Page fields describe a load module that is empty or larger than the file
+    InvalidImageSize {
+        expected: usize,
+        actual: usize,
+    },
+    /// Relocation points outside of the load module
+    RelocationOutOfImage(Relocation),
+    /// The program doesn't fit in conventional memory at the load segment
+    NoMemory {
+        segment: u16,
+        paragraphs: u32,
+    },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "cannot read file: {err}"),
+            LoadError::EmptyFile => write!(f, "file is empty"),
+            LoadError::ComTooLarge(size) => {
+                write!(f, ".COM program is too large ({size} bytes)")
+            }
+            LoadError::TruncatedHeader(size) => {
+                write!(f, "MZ header is truncated, file is only {size} bytes")
+            }
+            LoadError::InvalidHeaderSize(size) => {
+                write!(f, "invalid MZ header size of {size:#x} paragraphs")
+            }
+            LoadError::RelocationTableOutOfBounds { addr, count } => write!(
+                f,
+                "relocation table at {addr:#x} with {count} entries is outside of the header"
+            ),
+            LoadError::InvalidImageSize { expected, actual } => write!(
+                f,
+                "header expects {expected} bytes of program but file has {actual}"
+            ),
+            LoadError::RelocationOutOfImage(reloc) => write!(
+                f,
+                "relocation {:04x}:{:04x} is outside of the program",
+                reloc.segment, reloc.offset
+            ),
+            LoadError::NoMemory {
+                segment,
+                paragraphs,
+            } => write!(
+                f,
+                "not enough memory for {paragraphs:#x} paragraphs at segment {segment:04x}"
+            ),
+        }
+    }
+}
+
+impl From<LoadError> for DosError {
+    fn from(value: LoadError) -> Self {
+        match value {
+            LoadError::Io(err) => err.into(),
+            LoadError::NoMemory { .. } => DosError::InsufficientMemory,
+            _ => DosError::InvalidFormat,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(value: io::Error) -> Self {
+        LoadError::Io(value)
+    }
+}
+
+/// How the program file is laid out
+pub enum Format {
+    /// MZ executable with a header and relocations
+    Exe(Header),
+    /// Flat binary that is loaded at PSP:0100h
+    Com,
+}
 
 pub struct Program {
     // TODO: mapp the section header data directly here so it maps 1-1 with the program memory addresses
     data: Vec<u8>,
     /// Where does execution start
     start: u64,
-    header: Header,
+    format: Format,
+    /// Relocations that were applied when the program was loaded
+    fixups: Vec<Fixup>,
 }
 
 impl Program {
-    pub fn new(path: &str, start: u64) -> Self {
-        let mut data = read(path).unwrap();
-        let header = Header::new(&data);
-        data.drain(0..(header.header_size as usize * 16));
+    /// Paragraphs a .COM program gets, the whole 64K segment
+    const COM_SEGMENT: u16 = 0x1000;
+
+    /// Largest .COM image, the rest of the segment is for the PSP and the stack
+    const COM_MAX_SIZE: usize = 0xFF00 - 2;
+
+    pub fn new(path: &Path, start: u64) -> Result<Self, LoadError> {
+        let data = read(path)?;
+        Self::from_bytes(data, start)
+    }
+
+    pub fn from_bytes(data: Vec<u8>, start: u64) -> Result<Self, LoadError> {
+        if data.is_empty() {
+            return Err(LoadError::EmptyFile);
+        }
+
+        if !is_mz(&data) {
+            if data.len() > Self::COM_MAX_SIZE {
+                return Err(LoadError::ComTooLarge(data.len()));
+            }
+
+            return Ok(Self {
+                data,
+                start,
+                format: Format::Com,
+                fixups: vec![],
+            });
+        }
+
+        let header = Header::new(&data)?;
+        let header_bytes = header.header_size as usize * 16;
+        if header_bytes < Header::SIZE || header_bytes > data.len() {
+            return Err(LoadError::InvalidHeaderSize(header.header_size));
+        }
+
+        let table_end = header.relocation_addr as usize + header.relocation_table.len() * 4;
+        if !header.relocation_table.is_empty() && table_end > header_bytes {
+            return Err(LoadError::RelocationTableOutOfBounds {
+                addr: header.relocation_addr,
+                count: header.relocation_rows,
+            });
+        }
+
+        // Anything after the load module is overlay data and isn't loaded
+        let file_size = header.file_size();
+        if file_size <= header_bytes || file_size > data.len() {
+            return Err(LoadError::InvalidImageSize {
+                expected: file_size.saturating_sub(header_bytes),
+                actual: data.len() - header_bytes,
+            });
+        }
+        let mut data = data[header_bytes..file_size].to_vec();
+
+        let mut fixups = Vec::with_capacity(header.relocation_table.len());
         for reloc in &header.relocation_table {
-            let segment = reloc.segment as u64;
-            let offset = reloc.offset as u64;
-            let addr = (segment * 16 + offset) as usize;
-            let bytes = (start as u16).to_le_bytes();
-            data[addr] += bytes[0];
-            data[addr + 1] += bytes[1];
+            let addr = reloc.image_offset();
+            if addr + 2 > data.len() {
+                return Err(LoadError::RelocationOutOfImage(*reloc));
+            }
+            let original = LittleEndian::read_u16(&data[addr..addr + 2]);
+            let patched = original.wrapping_add(start as u16);
+            LittleEndian::write_u16(&mut data[addr..addr + 2], patched);
+            fixups.push(Fixup {
+                reloc: *reloc,
+                start: start as u16,
+                original,
+                patched,
+            });
         }
 
-        Self {
+        Ok(Self {
             data,
             start,
-            header,
-        }
+            format: Format::Exe(header),
+            fixups,
+        })
     }
 
     pub fn start(&self) -> u64 {
@@ -38,24 +192,123 @@ impl Program {
         &self.data
     }
 
-    pub fn header(&self) -> &Header {
-        &self.header
+    pub fn format(&self) -> &Format {
+        &self.format
+    }
+
+    /// Overlay number from the MZ header, 0 for the main program
+    pub fn overlay_number(&self) -> u16 {
+        match &self.format {
+            Format::Exe(header) => header.overlay,
+            Format::Com => 0,
+        }
+    }
+
+    pub fn fixups(&self) -> &[Fixup] {
+        &self.fixups
+    }
+
+    /// Segment of the PSP that is placed right before the program, overlays loaded below
+    /// segment 10h don't have one
+    pub fn psp_segment(&self) -> u64 {
+        self.start.saturating_sub(0x10)
+    }
+
+    /// Initial CS and IP
+    pub fn entry(&self) -> (u64, u64) {
+        match &self.format {
+            Format::Exe(header) => (
+                header.initial_cs as u64 + self.start,
+                header.initial_ip as u64,
+            ),
+            Format::Com => (self.psp_segment(), 0x100),
+        }
+    }
+
+    /// Initial SS and SP, .COM programs get the top of their segment
+    pub fn stack(&self) -> (u64, u64) {
+        match &self.format {
+            Format::Exe(header) => (
+                header.initial_ss as u64 + self.start,
+                header.initial_sp as u64,
+            ),
+            Format::Com => (self.psp_segment(), 0xFFFE),
+        }
+    }
+
+    /// Paragraphs needed for the PSP and the program, and the most the program wants.
+    ///
+    /// Fails when the program needs more than the 0FFFFh paragraphs a memory block can have.
+    pub fn allocation(&self) -> Result<(u16, u16), LoadError> {
+        let image = self.data.len().div_ceil(16) as u32 + 0x10;
+        let (min, max) = match &self.format {
+            Format::Exe(header) => (
+                image + header.min_allocation as u32,
+                image + header.max_allocation as u32,
+            ),
+            Format::Com => (image.max(Self::COM_SEGMENT as u32), 0xFFFF),
+        };
+        let min = u16::try_from(min).map_err(|_| LoadError::NoMemory {
+            segment: self.psp_segment() as u16,
+            paragraphs: min,
+        })?;
+        Ok((min, max.min(0xFFFF) as u16))
     }
 }
 
+/// MZ executables start with 'MZ', some old linkers wrote 'ZM'
+fn is_mz(data: &[u8]) -> bool {
+    matches!(data, [b'M', b'Z', ..] | [b'Z', b'M', ..])
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Relocation {
     offset: u16,
     segment: u16,
 }
 
+impl Relocation {
+    /// Position of the fixup from the start of the load module
+    fn image_offset(&self) -> usize {
+        self.segment as usize * 16 + self.offset as usize
+    }
+}
+
+/// Segment fixup applied to a word of the load module
+#[derive(Debug, Clone, Copy)]
+pub struct Fixup {
+    reloc: Relocation,
+    /// Segment the program was loaded at
+    start: u16,
+    original: u16,
+    patched: u16,
+}
+
+impl Display for Fixup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let site = self.start.wrapping_add(self.reloc.segment);
+        write!(
+            f,
+            "{:04x}:{:04x} at {:04x}:{:04x}: {:04x} -> {:04x}",
+            self.reloc.segment,
+            self.reloc.offset,
+            site,
+            self.reloc.offset,
+            self.original,
+            self.patched
+        )
+    }
+}
+
 pub struct Header {
     last_page_bytes: u16,
     pages_in_file: u16,
     relocation_rows: u16,
     header_size: u16,
-    min_allocation: u16,
-    max_allocation: u16,
+    /// Extra paragraphs the program needs after the load module
+    pub min_allocation: u16,
+    /// Extra paragraphs the program would like to have
+    pub max_allocation: u16,
     pub initial_ss: u16,
     pub initial_sp: u16,
     checksum: u16,
@@ -67,20 +320,35 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn new(bytes: &[u8]) -> Header {
+    /// Size of the fixed part of the header, the relocation table usually follows it
+    const SIZE: usize = 28;
+
+    pub fn new(bytes: &[u8]) -> Result<Header, LoadError> {
+        if bytes.len() < Self::SIZE {
+            return Err(LoadError::TruncatedHeader(bytes.len()));
+        }
+
         let relocations_count = LittleEndian::read_u16(&bytes[6..8]);
-        let mut relocations = vec![];
-
-        // This should really be worked out using relocation_addr, but eh
-        for n in 0..(relocations_count as usize) {
-            let n = n * 4;
-            relocations.push(Relocation {
-                offset: LittleEndian::read_u16(&bytes[(28 + n)..(30 + n)]),
-                segment: LittleEndian::read_u16(&bytes[(30 + n)..(32 + n)]),
-            })
+        let relocation_addr = LittleEndian::read_u16(&bytes[24..26]);
+        let table_start = relocation_addr as usize;
+        let table_end = table_start + relocations_count as usize * 4;
+        if relocations_count > 0 && (table_start < Self::SIZE || table_end > bytes.len()) {
+            return Err(LoadError::RelocationTableOutOfBounds {
+                addr: relocation_addr,
+                count: relocations_count,
+            });
         }
 
-        Header {
+        let table = bytes.get(table_start..table_end).unwrap_or_default();
+        let relocations = table
+            .chunks_exact(4)
+            .map(|entry| Relocation {
+                offset: LittleEndian::read_u16(&entry[0..2]),
+                segment: LittleEndian::read_u16(&entry[2..4]),
+            })
+            .collect();
+
+        Ok(Header {
             last_page_bytes: LittleEndian::read_u16(&bytes[2..4]),
             pages_in_file: LittleEndian::read_u16(&bytes[4..6]),
             relocation_rows: relocations_count,
@@ -92,9 +360,18 @@ impl Header {
             checksum: LittleEndian::read_u16(&bytes[18..20]),
             initial_ip: LittleEndian::read_u16(&bytes[20..22]),
             initial_cs: LittleEndian::read_u16(&bytes[22..24]),
-            relocation_addr: LittleEndian::read_u16(&bytes[24..26]),
+            relocation_addr,
             relocation_table: relocations,
             overlay: LittleEndian::read_u16(&bytes[26..28]),
+        })
+    }
+
+    /// Size of the header and the load module in bytes, worked out from the page fields
+    pub fn file_size(&self) -> usize {
+        let pages = self.pages_in_file as usize;
+        match self.last_page_bytes {
+            0 => pages * 512,
+            last => pages.saturating_sub(1) * 512 + last as usize,
         }
     }
 }
@@ -103,7 +380,7 @@ impl Header {
 mod tests {
     use byteorder::{ByteOrder, LittleEndian};
 
-    use crate::program::Header;
+    use crate::program::{Format, Header, LoadError, PSP, Program, parse_fcb};
 
     #[test]
     fn parse_header() {
@@ -124,7 +401,7 @@ mod tests {
             0x00, 0x00, 0xA0, 0xF5, 0x00, 0x00, 0x6E, 0x05, 0x00, 0x10, 0x7D, 0x01, 0x00, 0x10,
         ];
 
-        let header = Header::new(&header);
+        let header = Header::new(&header).unwrap();
 
         assert_eq!(
             header.last_page_bytes,
@@ -150,6 +427,118 @@ mod tests {
         assert_eq!(header.overlay, LittleEndian::read_u16(&[0x00, 0x00]));
         assert!(header.relocation_table.len() == 42);
     }
+
+    #[test]
+    fn load_com() {
+        let program = Program::from_bytes(vec![0xB4, 0x4C, 0xCD, 0x21], 0x1000).unwrap();
+
+        assert!(matches!(program.format(), Format::Com));
+        assert_eq!(program.entry(), (0x0FF0, 0x100));
+        assert_eq!(program.stack(), (0x0FF0, 0xFFFE));
+        assert_eq!(program.allocation().unwrap(), (0x1000, 0xFFFF));
+    }
+
+    #[test]
+    fn reject_malformed_exe() {
+        let mut exe = vec![0u8; 0x40];
+        exe[0..2].copy_from_slice(b"MZ");
+        // one page of 0x30 bytes with a two paragraph header
+        exe[2..4].copy_from_slice(&0x30u16.to_le_bytes());
+        exe[4..6].copy_from_slice(&1u16.to_le_bytes());
+        exe[8..10].copy_from_slice(&2u16.to_le_bytes());
+        exe[24..26].copy_from_slice(&0x1Cu16.to_le_bytes());
+
+        // trailing overlay data isn't part of the program
+        let program = Program::from_bytes(exe.clone(), 0x1000).unwrap();
+        assert_eq!(program.data().len(), 0x10);
+
+        assert!(matches!(
+            Program::from_bytes(exe[..0x10].to_vec(), 0x1000),
+            Err(LoadError::TruncatedHeader(0x10))
+        ));
+
+        let mut relocs = exe.clone();
+        relocs[6..8].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(
+            Program::from_bytes(relocs, 0x1000),
+            Err(LoadError::RelocationTableOutOfBounds { .. })
+        ));
+
+        let mut pages = exe.clone();
+        pages[4..6].copy_from_slice(&4u16.to_le_bytes());
+        assert!(matches!(
+            Program::from_bytes(pages, 0x1000),
+            Err(LoadError::InvalidImageSize { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_huge_load_module() {
+        // A megabyte load module and the PSP are more than a memory block can have
+        let size = 0x20 + 0xFFF00;
+        let mut exe = vec![0u8; size];
+        exe[0..2].copy_from_slice(b"MZ");
+        exe[2..4].copy_from_slice(&((size % 512) as u16).to_le_bytes());
+        exe[4..6].copy_from_slice(&(size.div_ceil(512) as u16).to_le_bytes());
+        exe[8..10].copy_from_slice(&2u16.to_le_bytes());
+        exe[24..26].copy_from_slice(&0x1Cu16.to_le_bytes());
+        let program = Program::from_bytes(exe, 0x1000).unwrap();
+        assert!(matches!(
+            program.allocation(),
+            Err(LoadError::NoMemory {
+                paragraphs: 0x10000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn relocation_fixups() {
+        // the carry from the low byte goes to the high byte and the word wraps around
+        let mut reloc = vec![0u8; 0x40];
+        reloc[0..2].copy_from_slice(b"MZ");
+        reloc[2..4].copy_from_slice(&0x40u16.to_le_bytes());
+        reloc[4..6].copy_from_slice(&1u16.to_le_bytes());
+        reloc[6..8].copy_from_slice(&3u16.to_le_bytes());
+        reloc[8..10].copy_from_slice(&3u16.to_le_bytes());
+        reloc[24..26].copy_from_slice(&0x1Cu16.to_le_bytes());
+        for (n, offset) in [0u16, 2, 4].iter().enumerate() {
+            let entry = 0x1C + n * 4;
+            reloc[entry..entry + 2].copy_from_slice(&offset.to_le_bytes());
+        }
+        reloc[0x32..0x34].copy_from_slice(&0x00F0u16.to_le_bytes());
+        reloc[0x34..0x36].copy_from_slice(&0xF123u16.to_le_bytes());
+        let program = Program::from_bytes(reloc, 0x1F20).unwrap();
+        assert_eq!(program.data()[0..2], 0x1F20u16.to_le_bytes());
+        assert_eq!(program.data()[2..4], 0x2010u16.to_le_bytes());
+        assert_eq!(program.data()[4..6], 0x1043u16.to_le_bytes());
+        assert_eq!(program.fixups().len(), 3);
+    }
+
+    #[test]
+    fn command_tail() {
+        let args = vec!["/a".to_string(), "foo.txt".to_string(), "b:*.c".to_string()];
+        let psp = PSP::new(0x2000, 0x0, 0x0, &args);
+
+        let len = psp.cmd_trail_chars as usize;
+        assert_eq!(&psp.cmd_trail[..len], b" /a foo.txt b:*.c");
+        assert_eq!(psp.cmd_trail[len], 0x0D);
+        assert_eq!(&psp.unopened_fcb_1[..12], b"\0FOO     TXT");
+        assert_eq!(&psp.unopened_fcb_2[..12], b"\x02????????C  ");
+
+        let long = vec!["x".repeat(200)];
+        let psp = PSP::new(0x2000, 0x0, 0x0, &long);
+        assert_eq!(psp.cmd_trail_chars, 126);
+        assert_eq!(psp.cmd_trail[126], 0x0D);
+    }
+
+    #[test]
+    fn fcb_names() {
+        assert_eq!(&parse_fcb("")[..12], b"\0           ");
+        assert_eq!(&parse_fcb("readme")[..12], b"\0README     ");
+        assert_eq!(&parse_fcb("c:verylongname.text")[..12], b"\x03VERYLONGTEX");
+        assert_eq!(&parse_fcb("ab*.t*")[..12], b"\0AB??????T??");
+    }
 }
 
 #[repr(C)]
@@ -205,16 +594,25 @@ pub struct PSP {
 }
 
 impl PSP {
-    pub fn new(alloc_end: u16, call_disp: u8) -> Self {
-        let mut cmd_trail: [u8; 127] = [0x0; 127];
-        let cmd = String::from(" list");
+    /// Longest command tail that fits before the terminating carriage return
+    const MAX_TAIL: usize = 126;
 
-        let mut count = 0;
-        for i in cmd.as_bytes() {
-            cmd_trail[count] = *i;
-            count += 1;
+    pub fn new(alloc_end: u16, call_disp: u8, env_segment: u16, args: &[String]) -> Self {
+        let mut cmd_trail: [u8; 127] = [0x0; 127];
+        let mut cmd = Vec::new();
+        for arg in args {
+            cmd.push(b' ');
+            cmd.extend_from_slice(arg.as_bytes());
         }
-        cmd_trail[count] = 0x0D;
+        cmd.truncate(Self::MAX_TAIL);
+
+        cmd_trail[..cmd.len()].copy_from_slice(&cmd);
+        cmd_trail[cmd.len()] = 0x0D;
+
+        // COMMAND.COM skips switches when it fills the default FCBs
+        let mut params = args.iter().filter(|arg| !arg.starts_with('/'));
+        let unopened_fcb_1 = params.next().map_or(parse_fcb(""), |arg| parse_fcb(arg));
+        let unopened_fcb_2 = params.next().map_or(parse_fcb(""), |arg| parse_fcb(arg));
 
         Self {
             exit_interrupt: 0x20CD,
@@ -227,17 +625,17 @@ impl PSP {
             crit_err_addr: 0x9999_9999,
             parent_addr: 0x0,
             file_handle_array: [0; 20],
-            env_segment_addr: 0x0,
+            env_segment_addr: env_segment,
             file_handle_size: 0x0,
             file_handle_addr: 0x9999_9999,
             prev_psp: 0x0,
             spacer: [0x0; 14],
             dispatcher: [0x0; 3],
             spacer_2: [0x0; 9],
-            unopened_fcb_1: [0x0; 16],
-            unopened_fcb_2: [0x0; 16],
-            cmd_trail_chars: cmd.chars().count() as u8,
-            cmd_trail: cmd_trail,
+            unopened_fcb_1,
+            unopened_fcb_2,
+            cmd_trail_chars: cmd.len() as u8,
+            cmd_trail,
             stack_save: 0x0,
             interim_flag: 0x0,
             truename_flag: 0x0,
@@ -246,6 +644,93 @@ impl PSP {
             spacer_3: [0x0; 4],
         }
     }
+
+    /// Offset of the terminate address in the PSP
+    pub const TERM_ADDR: u64 = 0x0A;
+    /// Offset of the parent PSP segment
+    pub const PARENT: u64 = 0x16;
+    /// Offset of the environment segment
+    pub const ENV_SEGMENT: u64 = 0x2C;
+    /// Offset of the SS:SP saved when the program starts a child
+    pub const STACK_SAVE: u64 = 0x2E;
+
+    /// Link a child PSP to the parent and the far address the child returns to
+    pub fn set_parent(&mut self, parent: u16, term_segment: u16, term_offset: u16) {
+        self.parent_addr = parent;
+        self.term_addr = ((term_segment as u32) << 16) | term_offset as u32;
+    }
+
+    /// Set the command tail from the length prefixed string given to EXEC
+    pub fn set_command_tail(&mut self, tail: &[u8]) {
+        let len = tail.len().min(Self::MAX_TAIL);
+        self.cmd_trail = [0; 127];
+        self.cmd_trail[..len].copy_from_slice(&tail[..len]);
+        self.cmd_trail[len] = 0x0D;
+        self.cmd_trail_chars = len as u8;
+    }
+
+    pub fn set_fcbs(&mut self, fcb_1: [u8; 16], fcb_2: [u8; 16]) {
+        self.unopened_fcb_1 = fcb_1;
+        self.unopened_fcb_2 = fcb_2;
+    }
+}
+
+/// Fill an unopened FCB from a command line parameter like INT 21h AH=29h does.
+///
+/// The drive is 0 for the default drive and 1 for A:, the name and extension are
+/// padded with spaces and `*` is expanded to `?` wildcards.
+fn parse_fcb(arg: &str) -> [u8; 16] {
+    let mut fcb = [0u8; 16];
+    let mut rest = arg;
+    if let [letter, b':', ..] = arg.as_bytes()
+        && letter.is_ascii_alphabetic()
+    {
+        fcb[0] = letter.to_ascii_uppercase() - b'A' + 1;
+        rest = &arg[2..];
+    }
+
+    fcb[1..12].copy_from_slice(&fcb_name(rest));
+    fcb
+}
+
+/// Name and extension of a file name in the space padded FCB format, e.g. `README  TXT`.
+///
+/// `*` is expanded to `?` wildcards so the result can be used as a search pattern.
+pub fn fcb_name(name: &str) -> [u8; 11] {
+    let mut fcb = [b' '; 11];
+    let name = name.as_bytes();
+    let used = fill_fcb_field(&mut fcb[..8], name);
+    if let [b'.', ext @ ..] = &name[used..] {
+        fill_fcb_field(&mut fcb[8..], ext);
+    }
+    fcb
+}
+
+fn is_fcb_terminator(c: &u8) -> bool {
+    b".:;,=+ \t/\"[]<>|".contains(c) || *c < 0x20
+}
+
+/// Copy a name or extension into an FCB field, returns how many bytes of `part` were consumed
+fn fill_fcb_field(field: &mut [u8], part: &[u8]) -> usize {
+    let end = part
+        .iter()
+        .position(is_fcb_terminator)
+        .unwrap_or(part.len());
+
+    for (idx, c) in part[..end].iter().enumerate() {
+        if *c == b'*' {
+            // the rest of the field is a wildcard and the remaining characters are ignored
+            if idx < field.len() {
+                field[idx..].fill(b'?');
+            }
+            break;
+        }
+        if idx < field.len() {
+            field[idx] = c.to_ascii_uppercase();
+        }
+    }
+
+    end
 }
 
 unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
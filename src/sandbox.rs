@@ -0,0 +1,183 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{drive::drive_letter, files::DosError};
+
+/// What the program did to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Modified,
+    Deleted,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Change::Created => "created",
+            Change::Modified => "modified",
+            Change::Deleted => "deleted",
+        };
+        f.pad(name)
+    }
+}
+
+/// Files the program has changed by their DOS path, for the report printed at exit
+pub struct Changes {
+    files: BTreeMap<String, Change>,
+}
+
+impl Changes {
+    pub fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Record a change, a file that was created and deleted again isn't reported
+    pub fn record(&mut self, name: String, change: Change) {
+        let change = match (self.files.get(&name), change) {
+            (Some(Change::Created), Change::Modified) => Some(Change::Created),
+            (Some(Change::Created), Change::Deleted) => None,
+            (Some(Change::Deleted), Change::Created) => Some(Change::Modified),
+            (_, change) => Some(change),
+        };
+        match change {
+            Some(change) => self.files.insert(name, change),
+            None => self.files.remove(&name),
+        };
+    }
+
+    pub fn report(&self) -> String {
+        if self.files.is_empty() {
+            return "The program didn't change any files\n".into();
+        }
+
+        let mut report = String::from("Files changed by the program:\n");
+        for (name, change) in &self.files {
+            report += &format!("    {change:<8} {name}\n");
+        }
+        report
+    }
+}
+
+/// Copy-on-write layer over the drives, `<dir>/<drive letter>` has the files the program
+/// created or changed and deleted files are hidden from the drive
+pub struct Overlay {
+    dir: PathBuf,
+    /// Files by drive and host path relative to the drive root
+    deleted: HashSet<(u8, PathBuf)>,
+}
+
+impl Overlay {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            deleted: HashSet::new(),
+        })
+    }
+
+    /// Where the overlay keeps a file of the drive
+    pub fn upper(&self, drive: u8, relative: &Path) -> PathBuf {
+        self.dir
+            .join(drive_letter(drive).to_string())
+            .join(relative)
+    }
+
+    pub fn is_deleted(&self, drive: u8, relative: &Path) -> bool {
+        self.deleted.contains(&(drive, relative.into()))
+    }
+
+    pub fn delete(&mut self, drive: u8, relative: &Path) {
+        self.deleted.insert((drive, relative.into()));
+    }
+
+    /// A deleted file is created again, when it was a directory the files that were in
+    /// it on the drive stay deleted
+    pub fn restore(&mut self, drive: u8, relative: &Path, lower: &Path) {
+        if !self.deleted.remove(&(drive, relative.into())) || !lower.is_dir() {
+            return;
+        }
+        for name in host_names(lower) {
+            self.delete(drive, &relative.join(name));
+        }
+    }
+}
+
+/// Names of the entries in a host directory
+pub fn host_names(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect()
+}
+
+/// Copy a file to the overlay, the copy keeps the modification time
+pub fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to)?;
+    let modified = fs::metadata(from)?.modified()?;
+    // The owner can set the time even if the copy is read-only
+    File::open(to)?.set_modified(modified)
+}
+
+/// Make sure a host path stays inside one of the roots after following symlinks.
+///
+/// The path doesn't have to exist, the part of it that does is checked. A dangling
+/// symlink is rejected since creating the file would follow it.
+pub fn confine(host: PathBuf, roots: &[PathBuf]) -> Result<PathBuf, DosError> {
+    let mut existing = host.as_path();
+    let real = loop {
+        if let Ok(real) = existing.canonicalize() {
+            break real;
+        }
+        if existing.symlink_metadata().is_ok() {
+            return Err(DosError::AccessDenied);
+        }
+        existing = existing.parent().ok_or(DosError::AccessDenied)?;
+    };
+
+    let inside = roots
+        .iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .any(|root| real.starts_with(root));
+    if inside {
+        Ok(host)
+    } else {
+        Err(DosError::AccessDenied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Changes};
+
+    #[test]
+    fn change_report() {
+        let mut changes = Changes::new();
+        assert_eq!(changes.report(), "The program didn't change any files\n");
+
+        changes.record("C:\\OUT.TXT".into(), Change::Created);
+        changes.record("C:\\OUT.TXT".into(), Change::Modified);
+        changes.record("C:\\TMP.$$$".into(), Change::Created);
+        changes.record("C:\\TMP.$$$".into(), Change::Deleted);
+        changes.record("C:\\README.TXT".into(), Change::Deleted);
+        changes.record("C:\\README.TXT".into(), Change::Created);
+        changes.record("C:\\OLD.BAK".into(), Change::Deleted);
+        assert_eq!(
+            changes.report(),
+            "Files changed by the program:\n    \
+             deleted  C:\\OLD.BAK\n    \
+             created  C:\\OUT.TXT\n    \
+             modified C:\\README.TXT\n"
+        );
+    }
+}
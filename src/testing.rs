@@ -0,0 +1,53 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    cli::CliArgs,
+    engine::{Engine, EngineData},
+    program::Program,
+};
+
+/// Temporary directory that is removed when the test ends, also when it fails
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Emulator with a COM program loaded at segment 1000h and `dir` as drive C:, the
+/// services are called directly without running any code
+pub fn emulator(dir: &Path) -> Unicorn<'static, EngineData> {
+    let args = CliArgs::parse_from([
+        "dos".as_ref(),
+        "--host-dir".as_ref(),
+        dir.as_os_str(),
+        "TEST.COM".as_ref(),
+    ]);
+    let program = Program::from_bytes(vec![0xCD, 0x20], args.load_segment as u64).unwrap();
+    Engine::new(program, &args).unwrap().into_emulator()
+}
+
+pub fn set_regs(emu: &mut Unicorn<EngineData>, regs: &[(RegisterX86, u64)]) {
+    for (reg, value) in regs {
+        emu.reg_write(*reg, *value).unwrap();
+    }
+}
+
+pub fn carry(emu: &Unicorn<EngineData>) -> bool {
+    emu.reg_read(RegisterX86::EFLAGS).unwrap() & 1 != 0
+}
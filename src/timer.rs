@@ -0,0 +1,373 @@
+use std::ops::RangeInclusive;
+
+use chrono::{Datelike, NaiveTime, Timelike};
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    bios,
+    clock::{self, from_bcd, to_bcd},
+    dos::{abort, set_carry},
+    engine::{Cpu, EngineData},
+    ports::PortDevice,
+};
+
+/// Input clock of the 8253 PIT in Hz
+pub const PIT_HZ: u64 = 1_193_182;
+/// Instructions per second when `--ips` isn't given, roughly a 386
+pub const DEFAULT_IPS: u64 = 1_000_000;
+
+/// Timer ticks since midnight in the BIOS data area and the flag set when they roll over
+const BDA_TICKS: u64 = 0x46C;
+const BDA_MIDNIGHT: u64 = 0x470;
+/// Ticks in a day at the 18.2 Hz rate
+const TICKS_PER_DAY: u32 = 0x1800B0;
+
+pub const PORT_CHANNEL_0: u16 = 0x40;
+pub const PORT_CONTROL: u16 = 0x43;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Low,
+    High,
+    /// Low byte followed by the high byte
+    Word,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    /// Count the channel counts down from, 0 means 65536
+    reload: u16,
+    access: Access,
+    mode: u8,
+    /// PIT tick the count was loaded at
+    loaded_at: u64,
+    /// Low byte of a word that is being written
+    write_low: Option<u8>,
+    /// Next read of a word access returns the high byte
+    read_high: bool,
+    /// Count latched with the control word
+    latch: Option<u16>,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            reload: 0,
+            access: Access::Word,
+            mode: 3,
+            loaded_at: 0,
+            write_low: None,
+            read_high: false,
+            latch: None,
+        }
+    }
+
+    fn period(&self) -> u64 {
+        if self.reload == 0 {
+            0x10000
+        } else {
+            self.reload as u64
+        }
+    }
+
+    fn count(&self, now: u64) -> u16 {
+        let elapsed = now - self.loaded_at;
+        // The square wave mode counts down by two, once for each half of the period
+        if self.mode == 3 {
+            let elapsed = (elapsed * 2) % self.period();
+            ((self.period() - elapsed) & !1) as u16
+        } else {
+            (self.period() - elapsed % self.period()) as u16
+        }
+    }
+}
+
+/// 8253 programmable interval timer, time advances with the instructions the program runs
+/// so runs are deterministic.
+pub struct Pit {
+    channels: [Channel; 3],
+    /// Instructions per emulated second
+    ips: u64,
+    /// Instruction count when the next IRQ0 is due
+    next_irq: u64,
+}
+
+impl Pit {
+    pub fn new(ips: u64) -> Self {
+        let mut pit = Self {
+            channels: [Channel::new(); 3],
+            ips: ips.max(1),
+            next_irq: 0,
+        };
+        pit.schedule(0);
+        pit
+    }
+
+    /// PIT ticks that have passed after running the instructions
+    fn ticks(&self, instructions: u64) -> u64 {
+        (instructions as u128 * PIT_HZ as u128 / self.ips as u128) as u64
+    }
+
+    fn instructions(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.ips as u128).div_ceil(PIT_HZ as u128) as u64
+    }
+
+    /// Find the instruction count of the next channel 0 period after `instructions`
+    fn schedule(&mut self, instructions: u64) {
+        let channel = &self.channels[0];
+        let now = self.ticks(instructions);
+        let periods = (now - channel.loaded_at) / channel.period() + 1;
+        let tick = channel.loaded_at + periods * channel.period();
+        self.next_irq = self.instructions(tick);
+    }
+
+    /// Write to the control word register (port 43h)
+    fn write_control(&mut self, value: u8, instructions: u64) {
+        let now = self.ticks(instructions);
+        let idx = (value >> 6) as usize;
+        // Read back command of the 8254 isn't supported
+        let Some(channel) = self.channels.get_mut(idx) else {
+            return;
+        };
+
+        let access = match (value >> 4) & 0b11 {
+            0 => {
+                channel.latch.get_or_insert(channel.count(now));
+                return;
+            }
+            1 => Access::Low,
+            2 => Access::High,
+            _ => Access::Word,
+        };
+        channel.access = access;
+        channel.mode = (value >> 1) & 0b111;
+        channel.write_low = None;
+        channel.read_high = false;
+        channel.latch = None;
+    }
+
+    /// Write to a counter (ports 40h-42h), the count starts when it has been fully written
+    fn write_counter(&mut self, idx: usize, value: u8, instructions: u64) {
+        let now = self.ticks(instructions);
+        let channel = &mut self.channels[idx];
+        let reload = match (channel.access, channel.write_low) {
+            (Access::Low, _) => value as u16,
+            (Access::High, _) => (value as u16) << 8,
+            (Access::Word, None) => {
+                channel.write_low = Some(value);
+                return;
+            }
+            (Access::Word, Some(low)) => u16::from_le_bytes([low, value]),
+        };
+        channel.write_low = None;
+        channel.reload = reload;
+        channel.loaded_at = now;
+        if idx == 0 {
+            self.schedule(instructions);
+        }
+    }
+
+    /// Read a counter (ports 40h-42h), the latched count if there is one
+    fn read_counter(&mut self, idx: usize, instructions: u64) -> u8 {
+        let now = self.ticks(instructions);
+        let channel = &mut self.channels[idx];
+        let count = channel.latch.unwrap_or_else(|| channel.count(now));
+        let [low, high] = count.to_le_bytes();
+        match channel.access {
+            Access::Low => {
+                channel.latch = None;
+                low
+            }
+            Access::High => {
+                channel.latch = None;
+                high
+            }
+            Access::Word if channel.read_high => {
+                channel.read_high = false;
+                channel.latch = None;
+                high
+            }
+            Access::Word => {
+                channel.read_high = true;
+                low
+            }
+        }
+    }
+}
+
+impl PortDevice for Pit {
+    fn name(&self) -> &'static str {
+        "PIT"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &[PORT_CHANNEL_0..=PORT_CONTROL]
+    }
+
+    fn read(&mut self, port: u16, now: u64) -> u8 {
+        match port {
+            PORT_CONTROL => 0xff,
+            _ => self.read_counter((port - PORT_CHANNEL_0) as usize, now),
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8, now: u64) {
+        match port {
+            PORT_CONTROL => self.write_control(value, now),
+            _ => self.write_counter((port - PORT_CHANNEL_0) as usize, value, now),
+        }
+    }
+
+    fn irq_line(&self) -> Option<u8> {
+        Some(0)
+    }
+
+    /// IRQ0 is due, the next one is scheduled when it is taken
+    fn irq_pending(&self, now: u64) -> bool {
+        now >= self.next_irq
+    }
+
+    fn irq_taken(&mut self, now: u64) {
+        self.schedule(now);
+    }
+}
+
+/// Timer ticks of the time of day at the 18.2 Hz rate
+fn ticks_since_midnight(time: NaiveTime) -> u32 {
+    let micros = time.num_seconds_from_midnight() as u64 * 1_000_000
+        + (time.nanosecond() / 1000).min(999_999) as u64;
+    (micros as u128 * PIT_HZ as u128 / 0x10000 / 1_000_000) as u32
+}
+
+/// Set the tick count in the BIOS data area from the time of day
+fn set_ticks(emu: &mut Unicorn<EngineData>, time: NaiveTime) {
+    let ticks = ticks_since_midnight(time);
+    emu.mem_write(BDA_TICKS, &ticks.to_le_bytes()).unwrap();
+    emu.mem_write(BDA_MIDNIGHT, &[0]).unwrap();
+}
+
+/// Start the BIOS tick count at the clock's time of day
+pub fn init(emu: &mut Unicorn<EngineData>) {
+    let time = emu.get_data().clock.now(0).time();
+    set_ticks(emu, time);
+}
+
+/// BIOS timer interrupt, counts the ticks since midnight and calls the user tick INT 1Ch
+pub fn int08(emu: &mut Unicorn<EngineData>) {
+    emu.get_data_mut().ports.pic.end_of_interrupt();
+
+    let mut ticks = [0u8; 4];
+    emu.mem_read(BDA_TICKS, &mut ticks).unwrap();
+    // The program can write any count to the BIOS data area
+    let mut ticks = u32::from_le_bytes(ticks).wrapping_add(1);
+    if ticks >= TICKS_PER_DAY {
+        ticks = 0;
+        emu.mem_write(BDA_MIDNIGHT, &[1]).unwrap();
+    }
+    emu.mem_write(BDA_TICKS, &ticks.to_le_bytes()).unwrap();
+
+    // The stub returns to the INT 1Ch handler's IRET, which then returns from the IRQ
+    let (segment, offset) = bios::vector(emu, 0x1C);
+    if (segment, offset) != bios::stub_address(0x1C) {
+        bios::deliver(emu, segment, offset);
+    }
+}
+
+/// Time of day and RTC services (INT 1Ah), the RTC is the same clock the DOS functions use
+pub fn int1a(emu: &mut Unicorn<EngineData>) {
+    let cpu = Cpu::read_engine(emu);
+    let ah = cpu.ax >> 8;
+    let [dl, dh] = (cpu.dx as u16).to_le_bytes();
+    let [cl, ch] = (cpu.cx as u16).to_le_bytes();
+    let instructions = emu.get_data().instructions;
+    let now = emu.get_data().clock.now(instructions);
+    if ah == 0x00 {
+        let mut ticks = [0u8; 4];
+        emu.mem_read(BDA_TICKS, &mut ticks).unwrap();
+        let ticks = u32::from_le_bytes(ticks);
+        // Reading the tick count clears the midnight flag
+        let midnight = emu.mem_read_as_vec(BDA_MIDNIGHT, 1).unwrap()[0];
+        emu.mem_write(BDA_MIDNIGHT, &[0]).unwrap();
+        emu.reg_write(RegisterX86::CX, (ticks >> 16) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DX, (ticks & 0xffff) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::AL, midnight as u64).unwrap();
+    } else if ah == 0x01 {
+        let ticks = ((cpu.cx as u32) << 16) | cpu.dx as u32;
+        emu.mem_write(BDA_TICKS, &ticks.to_le_bytes()).unwrap();
+        emu.mem_write(BDA_MIDNIGHT, &[0]).unwrap();
+    } else if ah == 0x02 {
+        emu.reg_write(RegisterX86::CH, to_bcd(now.hour()) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::CL, to_bcd(now.minute()) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DH, to_bcd(now.second()) as u64)
+            .unwrap();
+        // No daylight saving
+        emu.reg_write(RegisterX86::DL, 0).unwrap();
+        set_carry(emu, false);
+    } else if ah == 0x03 {
+        let time = clock::time(
+            from_bcd(ch) as u8,
+            from_bcd(cl) as u8,
+            from_bcd(dh) as u8,
+            0,
+        );
+        if let Some(time) = time {
+            let data = emu.get_data_mut();
+            data.clock.set(now.date().and_time(time), instructions);
+        }
+        set_carry(emu, time.is_none());
+    } else if ah == 0x04 {
+        emu.reg_write(RegisterX86::CH, to_bcd(now.year() as u32 / 100) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::CL, to_bcd(now.year() as u32 % 100) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DH, to_bcd(now.month()) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DL, to_bcd(now.day()) as u64)
+            .unwrap();
+        set_carry(emu, false);
+    } else if ah == 0x05 {
+        let year = from_bcd(ch) * 100 + from_bcd(cl);
+        let date = clock::date(year as u16, from_bcd(dh) as u8, from_bcd(dl) as u8);
+        if let Some(date) = date {
+            let data = emu.get_data_mut();
+            data.clock.set(date.and_time(now.time()), instructions);
+        }
+        set_carry(emu, date.is_none());
+    } else {
+        abort(emu, &format!("Unimplemented ah for 0x1a: 0x{ah:x}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PIT_HZ, Pit};
+    use crate::ports::PortDevice;
+
+    #[test]
+    fn counter_and_irq() {
+        // One instruction per PIT tick keeps the numbers easy to follow
+        let mut pit = Pit::new(PIT_HZ);
+        assert!(!pit.irq_pending(0xffff));
+        assert!(pit.irq_pending(0x10000));
+
+        // Channel 0 in mode 2 with a count of 1000
+        pit.write(0x43, 0b0011_0100, 100);
+        pit.write(0x40, 0xe8, 100);
+        pit.write(0x40, 0x03, 100);
+        assert!(!pit.irq_pending(1099));
+        assert!(pit.irq_pending(1100));
+        pit.irq_taken(1100);
+        assert!(!pit.irq_pending(2099));
+        assert!(pit.irq_pending(2100));
+
+        // Latch the count and read it even after time has passed
+        pit.write(0x43, 0b0000_0000, 350);
+        assert_eq!(pit.read(0x40, 400), 0xee);
+        assert_eq!(pit.read(0x40, 500), 0x02);
+        assert_eq!(pit.read(0x40, 500), 0x58);
+    }
+}
@@ -0,0 +1,322 @@
+use unicorn_engine::{RegisterX86, Unicorn};
+
+use crate::{
+    console,
+    dos::abort,
+    engine::{Cpu, EngineData},
+};
+
+/// Text buffer of the color modes, mode 7 uses the monochrome buffer at B000h
+const COLOR_SEGMENT: u64 = 0xB800;
+const MONO_SEGMENT: u64 = 0xB000;
+/// Light gray on black
+const DEFAULT_ATTRIBUTE: u8 = 0x07;
+const ROWS: u8 = 25;
+const PAGES: u8 = 8;
+
+/// Video variables in the BIOS data area
+const BDA_MODE: u64 = 0x449;
+const BDA_COLUMNS: u64 = 0x44A;
+const BDA_PAGE_SIZE: u64 = 0x44C;
+const BDA_PAGE_OFFSET: u64 = 0x44E;
+const BDA_CURSOR: u64 = 0x450;
+const BDA_CURSOR_SHAPE: u64 = 0x460;
+const BDA_PAGE: u64 = 0x462;
+const BDA_CRTC_PORT: u64 = 0x463;
+const BDA_ROWS: u64 = 0x484;
+
+/// Cursor from scan line 6 to 7, the underline cursor of the color modes
+const DEFAULT_CURSOR_SHAPE: u16 = 0x0607;
+
+fn read_byte(emu: &Unicorn<EngineData>, addr: u64) -> u8 {
+    emu.mem_read_as_vec(addr, 1).unwrap()[0]
+}
+
+fn write_byte(emu: &mut Unicorn<EngineData>, addr: u64, value: u8) {
+    emu.mem_write(addr, &[value]).unwrap();
+}
+
+fn write_word(emu: &mut Unicorn<EngineData>, addr: u64, value: u16) {
+    emu.mem_write(addr, &value.to_le_bytes()).unwrap();
+}
+
+/// Current text mode layout read from the BIOS data area
+struct Screen {
+    mode: u8,
+    columns: u8,
+    page_size: u16,
+}
+
+impl Screen {
+    fn read(emu: &Unicorn<EngineData>) -> Self {
+        let mut page_size = [0u8; 2];
+        emu.mem_read(BDA_PAGE_SIZE, &mut page_size).unwrap();
+        Self {
+            mode: read_byte(emu, BDA_MODE),
+            columns: read_byte(emu, BDA_COLUMNS),
+            page_size: u16::from_le_bytes(page_size),
+        }
+    }
+
+    fn segment(&self) -> u64 {
+        if self.mode == 7 {
+            MONO_SEGMENT
+        } else {
+            COLOR_SEGMENT
+        }
+    }
+
+    /// Linear address of the character cell, the attribute is the next byte
+    fn cell(&self, page: u8, row: u8, column: u8) -> u64 {
+        let offset = (row as u64 * self.columns as u64 + column as u64) * 2;
+        self.segment() * 16 + page as u64 * self.page_size as u64 + offset
+    }
+}
+
+fn cursor(emu: &Unicorn<EngineData>, page: u8) -> (u8, u8) {
+    let addr = BDA_CURSOR + (page % PAGES) as u64 * 2;
+    (read_byte(emu, addr + 1), read_byte(emu, addr))
+}
+
+fn set_cursor(emu: &mut Unicorn<EngineData>, page: u8, row: u8, column: u8) {
+    let addr = BDA_CURSOR + (page % PAGES) as u64 * 2;
+    write_byte(emu, addr, column);
+    write_byte(emu, addr + 1, row);
+}
+
+/// Set a text mode and clear the screen unless bit 7 of the mode is set (AH=00h)
+fn set_mode(emu: &mut Unicorn<EngineData>, mode: u8) {
+    let clear = mode & 0x80 == 0;
+    let mode = mode & 0x7f;
+    let columns: u8 = if mode <= 1 { 40 } else { 80 };
+    // Pages are rounded up to 2K or 4K like the BIOS does
+    let page_size: u16 = if columns == 40 { 0x800 } else { 0x1000 };
+    // Graphics modes only get the mode number stored, there is nothing to render them
+    if emu.get_data().verbose && !matches!(mode, 0..=3 | 7) {
+        println!("Graphics video mode 0x{mode:x} is not emulated");
+    }
+
+    write_byte(emu, BDA_MODE, mode);
+    write_word(emu, BDA_COLUMNS, columns as u16);
+    write_word(emu, BDA_PAGE_SIZE, page_size);
+    write_word(emu, BDA_PAGE_OFFSET, 0);
+    write_byte(emu, BDA_PAGE, 0);
+    write_byte(emu, BDA_ROWS, ROWS - 1);
+    let crtc = if mode == 7 { 0x3b4 } else { 0x3d4 };
+    write_word(emu, BDA_CRTC_PORT, crtc);
+    write_word(emu, BDA_CURSOR_SHAPE, DEFAULT_CURSOR_SHAPE);
+    for page in 0..PAGES {
+        set_cursor(emu, page, 0, 0);
+    }
+
+    if clear {
+        let screen = Screen::read(emu);
+        let blank = [b' ', DEFAULT_ATTRIBUTE].repeat(page_size as usize / 2 * PAGES as usize);
+        emu.mem_write(screen.cell(0, 0, 0), &blank).unwrap();
+    }
+}
+
+/// Start the emulation in 80x25 color text mode like the BIOS leaves it
+pub fn init(emu: &mut Unicorn<EngineData>) {
+    set_mode(emu, 3);
+}
+
+/// Move the rows inside the window up (positive `lines`) or down, the new rows are
+/// filled with spaces. Zero lines clears the whole window (AH=06h and 07h).
+fn scroll(
+    emu: &mut Unicorn<EngineData>,
+    lines: i16,
+    attribute: u8,
+    top: (u8, u8),
+    bottom: (u8, u8),
+) {
+    let screen = Screen::read(emu);
+    let page = read_byte(emu, BDA_PAGE);
+    let (top_row, left) = top;
+    let bottom_row = bottom.0.min(ROWS - 1);
+    let right = bottom.1.min(screen.columns - 1);
+    if top_row > bottom_row || left > right {
+        return;
+    }
+
+    let height = (bottom_row - top_row + 1) as i16;
+    let width = (right - left + 1) as usize * 2;
+    let lines = if lines == 0 || lines.abs() >= height {
+        height
+    } else {
+        lines
+    };
+    let blank = [b' ', attribute].repeat(width / 2);
+
+    for idx in 0..height {
+        // Scrolling down copies from the bottom up so rows aren't overwritten before they are moved
+        let row = if lines > 0 {
+            top_row as i16 + idx
+        } else {
+            bottom_row as i16 - idx
+        };
+        let source = row + lines;
+        let data = if (top_row as i16..=bottom_row as i16).contains(&source) {
+            emu.mem_read_as_vec(screen.cell(page, source as u8, left), width)
+                .unwrap()
+        } else {
+            blank.clone()
+        };
+        emu.mem_write(screen.cell(page, row as u8, left), &data)
+            .unwrap();
+    }
+}
+
+/// Write the character `count` times starting from the cursor without moving it (AH=09h and 0Ah)
+fn write_chars(emu: &mut Unicorn<EngineData>, page: u8, ch: u8, attribute: Option<u8>, count: u64) {
+    let screen = Screen::read(emu);
+    let (row, column) = cursor(emu, page);
+    let start = row as u64 * screen.columns as u64 + column as u64;
+    let end = (start + count).min(ROWS as u64 * screen.columns as u64);
+    for idx in start..end {
+        let row = (idx / screen.columns as u64) as u8;
+        let column = (idx % screen.columns as u64) as u8;
+        let addr = screen.cell(page, row, column);
+        write_byte(emu, addr, ch);
+        if let Some(attribute) = attribute {
+            write_byte(emu, addr + 1, attribute);
+        }
+    }
+}
+
+/// Teletype output, handles bell, backspace, line feed and carriage return and scrolls
+/// the screen when the cursor goes past the last row (AH=0Eh)
+pub fn teletype(emu: &mut Unicorn<EngineData>, ch: u8) {
+    let screen = Screen::read(emu);
+    let page = read_byte(emu, BDA_PAGE);
+    let (mut row, mut column) = cursor(emu, page);
+    match ch {
+        0x07 => {}
+        0x08 => column = column.saturating_sub(1),
+        0x0a => row = row.saturating_add(1),
+        0x0d => column = 0,
+        ch => {
+            write_byte(emu, screen.cell(page, row, column), ch);
+            column = column.saturating_add(1);
+            if column >= screen.columns {
+                column = 0;
+                row = row.saturating_add(1);
+            }
+        }
+    }
+
+    if row >= ROWS {
+        // The new line gets the attribute of the last row
+        let attribute = read_byte(emu, screen.cell(page, ROWS - 1, 0) + 1);
+        scroll(emu, 1, attribute, (0, 0), (ROWS - 1, screen.columns - 1));
+        row = ROWS - 1;
+    }
+    set_cursor(emu, page, row, column);
+}
+
+/// Handle video BIOS calls (INT 10h)
+pub fn int10(emu: &mut Unicorn<EngineData>) {
+    let cpu = Cpu::read_engine(emu);
+    let ah = cpu.ax >> 8;
+    let al = (cpu.ax & 0xff) as u8;
+    let bh = (cpu.bx >> 8) as u8;
+    let bl = (cpu.bx & 0xff) as u8;
+    if ah == 0x00 {
+        set_mode(emu, al);
+    } else if ah == 0x01 {
+        write_word(emu, BDA_CURSOR_SHAPE, cpu.cx as u16);
+    } else if ah == 0x02 {
+        set_cursor(emu, bh, (cpu.dx >> 8) as u8, cpu.dx as u8);
+    } else if ah == 0x03 {
+        let (row, column) = cursor(emu, bh);
+        let mut shape = [0u8; 2];
+        emu.mem_read(BDA_CURSOR_SHAPE, &mut shape).unwrap();
+        emu.reg_write(RegisterX86::CX, u16::from_le_bytes(shape) as u64)
+            .unwrap();
+        emu.reg_write(RegisterX86::DX, ((row as u64) << 8) | column as u64)
+            .unwrap();
+    } else if ah == 0x05 {
+        let screen = Screen::read(emu);
+        let page = al % PAGES;
+        write_byte(emu, BDA_PAGE, page);
+        write_word(emu, BDA_PAGE_OFFSET, page as u16 * screen.page_size);
+    } else if ah == 0x06 || ah == 0x07 {
+        let lines = al as i16;
+        let lines = if ah == 0x06 { lines } else { -lines };
+        let top = ((cpu.cx >> 8) as u8, cpu.cx as u8);
+        let bottom = ((cpu.dx >> 8) as u8, cpu.dx as u8);
+        scroll(emu, lines, bh, top, bottom);
+    } else if ah == 0x08 {
+        let screen = Screen::read(emu);
+        let (row, column) = cursor(emu, bh);
+        let cell = emu
+            .mem_read_as_vec(screen.cell(bh % PAGES, row, column), 2)
+            .unwrap();
+        let ax = ((cell[1] as u64) << 8) | cell[0] as u64;
+        emu.reg_write(RegisterX86::AX, ax).unwrap();
+    } else if ah == 0x09 {
+        write_chars(emu, bh % PAGES, al, Some(bl), cpu.cx);
+    } else if ah == 0x0a {
+        write_chars(emu, bh % PAGES, al, None, cpu.cx);
+    } else if ah == 0x0e {
+        teletype(emu, al);
+    } else if ah == 0x0f {
+        let screen = Screen::read(emu);
+        let page = read_byte(emu, BDA_PAGE);
+        let ax = ((screen.columns as u64) << 8) | screen.mode as u64;
+        emu.reg_write(RegisterX86::AX, ax).unwrap();
+        emu.reg_write(RegisterX86::BH, page as u64).unwrap();
+    } else {
+        abort(emu, &format!("Unimplemented ah for 0x10: 0x{ah:x}"));
+    }
+}
+
+/// CP437 glyphs the video card shows for the control characters 00h-1Fh
+const CONTROL_GLYPHS: [char; 32] = [
+    ' ', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+fn glyph(ch: u8) -> char {
+    match ch {
+        0x00..=0x1f => CONTROL_GLYPHS[ch as usize],
+        0x7f => '⌂',
+        ch => console::cp437_char(ch),
+    }
+}
+
+/// ANSI color numbers for the CGA colors, CGA has blue and red the other way around
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+fn ansi_attribute(attribute: u8) -> String {
+    let fg = attribute & 0x0f;
+    let bg = (attribute >> 4) & 0x07;
+    let fg = if fg >= 8 {
+        90 + ANSI_COLORS[fg as usize - 8]
+    } else {
+        30 + ANSI_COLORS[fg as usize]
+    };
+    format!("\x1b[{fg};{}m", 40 + ANSI_COLORS[bg as usize])
+}
+
+/// Render the visible text page with ANSI colors for the debugger
+pub fn render(emu: &Unicorn<EngineData>) -> String {
+    let screen = Screen::read(emu);
+    let page = read_byte(emu, BDA_PAGE);
+    let mut out = String::new();
+    for row in 0..ROWS {
+        let cells = emu
+            .mem_read_as_vec(screen.cell(page, row, 0), screen.columns as usize * 2)
+            .unwrap();
+        let mut current = None;
+        for cell in cells.chunks(2) {
+            if current != Some(cell[1]) {
+                current = Some(cell[1]);
+                out.push_str(&ansi_attribute(cell[1]));
+            }
+            out.push(glyph(cell[0]));
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}